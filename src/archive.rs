@@ -0,0 +1,261 @@
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use crate::codec::Codec;
+use crate::crypto::KeyProvider;
+use crate::error::Error;
+use crate::primitives::{Entry, LogFormat, Segment};
+use crate::segment::{SegmentFile, SegmentHeader};
+use crate::{create_dir, create_file, file_options, is_eof, read_fragment, read_fragments, skip_continued, skip_fragments, sync_dir};
+
+const MANIFEST: &str = "MANIFEST";
+
+// A segment moved into the archive, recorded as one line of the manifest.
+// first_index and last_index are the first and last entries starting in it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ArchivedSegment {
+    pub first_index: u64,
+    pub last_index: u64,
+    // number of the fragment of the entry before first_index the segment
+    // starts with, zero unless it continues that entry
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub fragment: u64,
+    pub file: String,
+    pub log_format: LogFormat,
+    pub compressed: bool,
+}
+
+// Archive keeps the segments dropped from the front of a log. Segments are
+// moved, or gzip compressed, into the archive directory and listed in its
+// manifest so their entries can still be read back by index.
+#[derive(Debug)]
+pub struct Archive {
+    pub path: PathBuf,
+    segments: Vec<ArchivedSegment>,
+    // codec for compressed entries besides the built-in ones
+    codec: Option<Arc<dyn Codec>>,
+    // keys for encrypted entries
+    keys: Option<Arc<dyn KeyProvider>>,
+    // mode of the files created in the archive
+    file_perms: Option<u32>,
+}
+
+impl Archive {
+    // Open the archive at dir for reading
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Archive, Error> {
+        let path = dir.as_ref().to_path_buf();
+        let mut archive = Archive {
+            path,
+            segments: Vec::new(),
+            codec: None,
+            keys: None,
+            file_perms: None,
+        };
+
+        let manifest = match File::open(archive.path.join(MANIFEST)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(archive),
+            Err(e) => return Err(Error::File(e)),
+        };
+
+        for line in BufReader::new(manifest).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let segment: ArchivedSegment = serde_json::from_str(&line).map_err(|_| Error::Corrupt)?;
+            archive.push(segment);
+        }
+
+        Ok(archive)
+    }
+
+    // Open the archive at dir, creating the directory with dir_perms when
+    // missing and files with file_perms
+    pub(crate) fn create<P: AsRef<Path>>(dir: P, dir_perms: Option<u32>, file_perms: Option<u32>) -> Result<Archive, Error> {
+        create_dir(dir.as_ref(), dir_perms)?;
+        let mut archive = Archive::open(dir)?;
+        archive.file_perms = file_perms;
+        Ok(archive)
+    }
+
+    // Use codec to decompress entries it compressed, for archives of logs
+    // configured with a custom codec
+    pub fn set_codec(&mut self, codec: Arc<dyn Codec>) {
+        self.codec = Some(codec);
+    }
+
+    // Use keys to decrypt entries, for archives of encrypted logs
+    pub fn set_keys(&mut self, keys: Arc<dyn KeyProvider>) {
+        self.keys = Some(keys);
+    }
+
+    pub fn segments(&self) -> &[ArchivedSegment] {
+        &self.segments
+    }
+
+    // Index of the first archived entry, zero when the archive is empty
+    pub fn first_index(&self) -> u64 {
+        self.segments.first().map_or(0, |s| s.first_index)
+    }
+
+    // Index of the last archived entry, zero when the archive is empty
+    pub fn last_index(&self) -> u64 {
+        self.segments.last().map_or(0, |s| s.last_index)
+    }
+
+    pub fn read(&self, index: u64) -> Result<Vec<u8>, Error> {
+        self.read_entry(index).map(|entry| entry.data)
+    }
+
+    // Read an archived entry. Every call scans the archived segment holding
+    // the entry from its start, and the segments it continues in.
+    pub fn read_entry(&self, index: u64) -> Result<Entry, Error> {
+        let mut pos = match self.find(index) {
+            Some(pos) => pos,
+            None => return Err(Error::NotFound),
+        };
+
+        let segment = &self.segments[pos];
+        let mut reader = self.reader(segment)?;
+        if segment.fragment > 0 {
+            skip_continued(&mut reader, &segment.log_format, segment.first_index - 1, segment.fragment)?;
+        }
+
+        loop {
+            let entry = match read_fragment(&mut reader, &segment.log_format) {
+                Err(ref e) if is_eof(e) => return Err(Error::Corrupt),
+                Err(e) => return Err(e),
+                Ok(entry) => entry,
+            };
+            if entry.index == index {
+                let next = |reader: &mut Box<dyn BufRead>, fragment| {
+                    match self.segments.get(pos + 1) {
+                        Some(next) if next.first_index == index + 1 && next.fragment == fragment => {
+                            pos += 1;
+                            *reader = self.reader(next)?;
+                            Ok(true)
+                        },
+                        _ => Ok(false),
+                    }
+                };
+                return read_fragments(&mut reader, &segment.log_format, entry, self.codec.as_ref(), self.keys.as_ref(), next);
+            }
+            if !skip_fragments(&mut reader, &segment.log_format, &entry)? {
+                return Err(Error::Corrupt);
+            }
+        }
+    }
+
+    // Position of the archived segment the entry at index starts in
+    fn find(&self, index: u64) -> Option<usize> {
+        let i = self.segments.partition_point(|s| s.last_index < index);
+        self.segments.get(i).filter(|s| s.first_index <= index).map(|_| i)
+    }
+
+    // Open the file of segment, positioned after its header
+    fn reader(&self, segment: &ArchivedSegment) -> Result<Box<dyn BufRead>, Error> {
+        let path = self.path.join(&segment.file);
+        let mut reader: Box<dyn BufRead> = if segment.compressed {
+            Box::new(BufReader::new(GzDecoder::new(File::open(path)?)))
+        } else {
+            Box::new(BufReader::new(SegmentFile::open(&path, self.codec.as_ref())?))
+        };
+        SegmentHeader::read(&mut reader)?;
+        Ok(reader)
+    }
+
+    fn push(&mut self, segment: ArchivedSegment) {
+        // a segment starting at or before archived entries replaces them,
+        // which only happens when the log was recreated from scratch
+        self.segments.retain(|s| s.last_index < segment.first_index);
+        self.segments.push(segment);
+    }
+
+    // Move the first size bytes of the file of segment, holding the entries
+    // up to last_index, into the archive. The file is left for the caller to
+    // remove.
+    pub(crate) fn add(&mut self, segment: &Segment, size: u64, last_index: u64, log_format: &LogFormat, compress: bool) -> Result<(), Error> {
+        let src = &segment.path;
+        let mut name = file_name(src);
+        if compress {
+            name.push_str(".gz");
+        }
+        let dst = self.path.join(&name);
+
+        let mut reader = SegmentFile::open(src, self.codec.as_ref())?.take(size);
+        let file = create_file(&dst, self.file_perms)?;
+        let file = if compress {
+            let mut encoder = GzEncoder::new(file, Compression::default());
+            io::copy(&mut reader, &mut encoder)?;
+            encoder.finish()?
+        } else {
+            let mut file = file;
+            io::copy(&mut reader, &mut file)?;
+            file
+        };
+        file.sync_all()?;
+
+        self.record(ArchivedSegment {
+            first_index: segment.first_entry(),
+            last_index,
+            fragment: segment.fragment,
+            file: name,
+            log_format: log_format.clone(),
+            compressed: compress,
+        })
+    }
+
+    // Move the whole file of segment into the archive, renaming it when no
+    // compression is wanted and the archive is on the same filesystem.
+    // Block compressed segments are renamed as is, or gzipped from their
+    // original bytes.
+    pub(crate) fn add_file(&mut self, segment: &Segment, last_index: u64, log_format: &LogFormat, compress: bool) -> Result<(), Error> {
+        let src = &segment.path;
+        let name = file_name(src);
+        if !compress && fs::rename(src, self.path.join(&name)).is_ok() {
+            File::open(self.path.join(&name))?.sync_all()?;
+            return self.record(ArchivedSegment {
+                first_index: segment.first_entry(),
+                last_index,
+                fragment: segment.fragment,
+                file: name,
+                log_format: log_format.clone(),
+                compressed: false,
+            });
+        }
+
+        // the original length, a block compressed segment is smaller on disk
+        let size = SegmentFile::open(src, self.codec.as_ref())?.raw_len()?;
+        self.add(segment, size, last_index, log_format, compress)?;
+        fs::remove_file(src)?;
+        Ok(())
+    }
+
+    // Append segment to the manifest, once its data is on disk
+    fn record(&mut self, segment: ArchivedSegment) -> Result<(), Error> {
+        let mut line = serde_json::to_vec(&segment).expect("serialise json");
+        line.push(b'\n');
+        let mut manifest = file_options(self.file_perms).create(true).append(true).open(self.path.join(MANIFEST))?;
+        manifest.write_all(&line)?;
+        manifest.sync_all()?;
+        sync_dir(&self.path)?;
+
+        self.push(segment);
+        Ok(())
+    }
+}
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
+// Archived files keep the name of the segment file they were moved from
+fn file_name(path: &Path) -> String {
+    path.file_name().expect("segment should have a file name").to_string_lossy().into_owned()
+}
@@ -1,7 +1,10 @@
-#[derive(Debug, Clone)]
+use crate::primitives::Header;
+
+#[derive(Debug, Default, Clone)]
 pub struct Batch {
     pub data_sizes: Vec<usize>,
-    pub datas: Vec<u8>
+    pub datas: Vec<u8>,
+    pub headers: Vec<Vec<Header>>
 }
 
 impl Batch {
@@ -9,16 +12,23 @@ impl Batch {
         Batch {
             data_sizes: Vec::new(),
             datas: Vec::new(),
+            headers: Vec::new(),
         }
     }
-    
+
     pub fn write<D: AsRef<[u8]>>(&mut self, data: D) {
+        self.write_with_headers(data, &[])
+    }
+
+    pub fn write_with_headers<D: AsRef<[u8]>>(&mut self, data: D, headers: &[Header]) {
         self.data_sizes.push(data.as_ref().len());
-        self.datas.extend(data.as_ref())
+        self.datas.extend(data.as_ref());
+        self.headers.push(headers.to_vec());
     }
 
     pub fn clear(&mut self) {
         self.datas.clear();
         self.data_sizes.clear();
+        self.headers.clear();
     }
 }
@@ -0,0 +1,39 @@
+// SegmentCache keeps a value for each of the most recently used segments,
+// keyed by the index of their first entry. The least recently used one is
+// evicted once it holds more than its capacity, but the segment in use is
+// always kept.
+#[derive(Debug)]
+pub(crate) struct SegmentCache<T> {
+    capacity: usize,
+    // most recently used first
+    items: Vec<(u64, T)>,
+}
+
+impl<T> SegmentCache<T> {
+    pub(crate) fn new(capacity: usize) -> SegmentCache<T> {
+        SegmentCache { capacity, items: Vec::new() }
+    }
+
+    pub(crate) fn get(&mut self, index: u64) -> Option<&T> {
+        let i = self.items.iter().position(|(k, _)| *k == index)?;
+        let item = self.items.remove(i);
+        self.items.insert(0, item);
+        self.items.first().map(|(_, v)| v)
+    }
+
+    pub(crate) fn insert(&mut self, index: u64, value: T) {
+        self.items.retain(|(k, _)| *k != index);
+        self.items.insert(0, (index, value));
+        self.items.truncate(self.capacity);
+    }
+
+    // Keep only the segments whose first index satisfies f
+    pub(crate) fn retain<F: Fn(u64) -> bool>(&mut self, f: F) {
+        self.items.retain(|(k, _)| f(*k));
+    }
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.items.len()
+    }
+}
@@ -0,0 +1,79 @@
+use flate2::Compression;
+use flate2::read::{DeflateDecoder, DeflateEncoder};
+use std::fmt;
+use std::io::Read;
+use std::sync::Arc;
+use crate::error::Error;
+
+// Codec compresses entry data. The id is stored with every entry the codec
+// compressed so the entry can be decompressed after the log switched to
+// another codec. Ids below 128 are reserved for the built-in codecs.
+pub trait Codec: fmt::Debug + Send + Sync {
+    fn id(&self) -> u8;
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>, Error>;
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, Error>;
+}
+
+// NoCompression stores the data as is
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoCompression;
+
+impl Codec for NoCompression {
+    fn id(&self) -> u8 {
+        0
+    }
+
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        Ok(data.to_vec())
+    }
+
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        Ok(data.to_vec())
+    }
+}
+
+// Deflate compresses with raw deflate at a level between 0 and 9
+#[derive(Debug, Clone, Copy)]
+pub struct Deflate {
+    pub level: u32,
+}
+
+impl Default for Deflate {
+    fn default() -> Deflate {
+        Deflate { level: 6 }
+    }
+}
+
+impl Codec for Deflate {
+    fn id(&self) -> u8 {
+        1
+    }
+
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut buf = Vec::new();
+        DeflateEncoder::new(data, Compression::new(self.level)).read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut buf = Vec::new();
+        DeflateDecoder::new(data).read_to_end(&mut buf).map_err(|_| Error::Corrupt)?;
+        Ok(buf)
+    }
+}
+
+// Returns the codec for id, looking at the configured codec before the
+// built-in ones.
+pub(crate) fn find_codec(id: u8, configured: Option<&Arc<dyn Codec>>) -> Result<Arc<dyn Codec>, Error> {
+    if let Some(codec) = configured {
+        if codec.id() == id {
+            return Ok(codec.clone());
+        }
+    }
+
+    match id {
+        0 => Ok(Arc::new(NoCompression)),
+        1 => Ok(Arc::new(Deflate::default())),
+        _ => Err(Error::UnknownCodec),
+    }
+}
@@ -0,0 +1,100 @@
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::RwLock;
+use crate::error::Error;
+
+pub const NONCE_SIZE: usize = 12;
+
+// KeyProvider hands out the 256 bit keys entries are encrypted with. Every
+// encrypted entry records the id of its key, so old keys must stay available
+// for as long as entries encrypted with them are read.
+pub trait KeyProvider: fmt::Debug + Send + Sync {
+    // id of the key new segments are encrypted with
+    fn current_key_id(&self) -> u32;
+    // key for id, None when the provider does not know it
+    fn key(&self, id: u32) -> Option<[u8; 32]>;
+}
+
+// StaticKeys keeps a fixed set of keys in memory
+#[derive(Default)]
+pub struct StaticKeys {
+    keys: RwLock<(u32, HashMap<u32, [u8; 32]>)>,
+}
+
+impl StaticKeys {
+    pub fn new(id: u32, key: [u8; 32]) -> StaticKeys {
+        let keys = StaticKeys::default();
+        keys.insert(id, key);
+        keys.set_current(id);
+        keys
+    }
+
+    pub fn insert(&self, id: u32, key: [u8; 32]) {
+        self.keys.write().expect("keys lock").1.insert(id, key);
+    }
+
+    // Encrypt segments created from now on with the key for id
+    pub fn set_current(&self, id: u32) {
+        self.keys.write().expect("keys lock").0 = id;
+    }
+}
+
+impl fmt::Debug for StaticKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // never print the keys themselves
+        let keys = self.keys.read().expect("keys lock");
+        f.debug_struct("StaticKeys")
+            .field("current", &keys.0)
+            .field("ids", &keys.1.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl KeyProvider for StaticKeys {
+    fn current_key_id(&self) -> u32 {
+        self.keys.read().expect("keys lock").0
+    }
+
+    fn key(&self, id: u32) -> Option<[u8; 32]> {
+        self.keys.read().expect("keys lock").1.get(&id).copied()
+    }
+}
+
+// Encrypt data with AES-256-GCM under a random nonce, authenticating aad
+// along with it. Returns the nonce and the ciphertext.
+pub(crate) fn encrypt(keys: &dyn KeyProvider, key_id: u32, aad: &[u8], data: &[u8]) -> Result<(Vec<u8>, Vec<u8>), Error> {
+    let key = keys.key(key_id).ok_or(Error::KeyNotFound)?;
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key));
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let data = cipher.encrypt(&nonce, Payload { msg: data, aad }).map_err(|_| Error::AuthenticationFailed)?;
+    Ok((nonce.to_vec(), data))
+}
+
+pub(crate) fn decrypt(keys: &dyn KeyProvider, key_id: u32, aad: &[u8], nonce: &[u8], data: &[u8]) -> Result<Vec<u8>, Error> {
+    if nonce.len() != NONCE_SIZE {
+        return Err(Error::Corrupt);
+    }
+    let key = keys.key(key_id).ok_or(Error::KeyNotFound)?;
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key));
+    cipher.decrypt(Nonce::from_slice(nonce), Payload { msg: data, aad }).map_err(|_| Error::AuthenticationFailed)
+}
+
+// Data authenticated along with fragment number fragment of the entry at
+// index, last telling whether it ends the entry, and meta the encoded
+// plaintext metadata stored next to it: timestamp, headers and codec id.
+// A whole entry without metadata is bound to its index alone, as before
+// entries were split. A fragment is bound to its number and whether it is
+// the last one too, so fragments can't be swapped, moved to another entry
+// or have the ones after them cut off. Metadata is readable by anyone but
+// can't be changed without failing authentication.
+pub(crate) fn aad(index: u64, fragment: u64, last: bool, meta: &[u8]) -> Vec<u8> {
+    let mut aad = index.to_be_bytes().to_vec();
+    if fragment > 0 || !last || !meta.is_empty() {
+        aad.extend_from_slice(&fragment.to_be_bytes());
+        aad.push(last as u8);
+    }
+    aad.extend_from_slice(meta);
+    aad
+}
@@ -9,6 +9,16 @@ pub enum Error {
     OutofOrder,
     OutOfRange,
     InMemoryLog,
+    InvalidHeader,
+    UnknownCodec,
+    AuthenticationFailed,
+    KeyNotFound,
+    FormatMismatch,
+    UnsupportedVersion,
+    Timeout,
+    InvalidOptions(&'static str),
+    InUse,
+    ReadOnly,
     File(std::io::Error)
 }
 
@@ -23,6 +33,16 @@ impl fmt::Display for Error {
             Error::OutofOrder => write!(f, "out of order"),
             Error::OutOfRange => write!(f, "out of range"),
             Error::InMemoryLog => write!(f, "in-memory log not supported"),
+            Error::InvalidHeader => write!(f, "invalid header"),
+            Error::UnknownCodec => write!(f, "unknown codec"),
+            Error::AuthenticationFailed => write!(f, "authentication failed"),
+            Error::KeyNotFound => write!(f, "encryption key not found"),
+            Error::FormatMismatch => write!(f, "segment format mismatch"),
+            Error::UnsupportedVersion => write!(f, "unsupported segment version"),
+            Error::Timeout => write!(f, "timed out"),
+            Error::InvalidOptions(reason) => write!(f, "invalid options: {}", reason),
+            Error::InUse => write!(f, "log in use by another process"),
+            Error::ReadOnly => write!(f, "log opened read-only"),
             Error::File(e) => write!(f, "file: {}", e),
         }
     }
@@ -33,3 +53,12 @@ impl From<std::io::Error> for Error {
         Error::File(error)
     }
 }
+
+impl From<Error> for std::io::Error {
+    fn from(error: Error) -> Self {
+        match error {
+            Error::File(e) => e,
+            e => io::Error::other(e),
+        }
+    }
+}
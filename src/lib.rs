@@ -1,34 +1,76 @@
 pub mod error;
 pub mod batch;
 pub mod primitives;
+pub mod archive;
+pub mod codec;
+pub mod segment;
+pub mod crypto;
+mod cache;
+mod lock;
+mod sync;
 
 use lazy_static::lazy_static;
-use std::fs::{self, File, create_dir_all};
-use std::io::{BufReader, BufWriter};
+use std::fs::{self, DirBuilder, File};
+use std::io::{BufRead, BufReader};
 use std::io::prelude::*;
 use crate::error::Error;
 use std::path::{Path, PathBuf};
-use std::fmt;
 use std::fs::OpenOptions;
 use std::io::SeekFrom;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::archive::Archive;
 use crate::batch::Batch;
+use crate::codec::{Codec, find_codec};
+use crate::crypto::{KeyProvider, NONCE_SIZE, aad, decrypt, encrypt};
+use crate::segment::{Bytes, CompressionStats, HEADER_SIZE, MappedSegment, SegmentFile, SegmentFooter, SegmentHeader, compress_segment, decompress_segment, preallocate, read_segment_header, write_footer};
 use crate::primitives::*;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex, MutexGuard};
+use crate::sync::{Syncer, Writer};
+use crate::cache::SegmentCache;
+use crate::lock::LockFile;
 
 lazy_static! {
     pub static ref DEFAULT_OPTIONS: Options = Options {
         durability: Durability::High,
         segment_size: 52428800, // 50 Mb log segment files
-        log_format: LogFormat::JSON
+        log_format: LogFormat::JSON,
+        timestamps: false,
+        retention: Retention::default(),
+        archive: None,
+        max_segment_age: None,
+        compression: None,
+        segment_compression: None,
+        encryption: None,
+        footer_offsets: false,
+        preallocate: false,
+        segment_pool: 0,
+        background_sync: None,
+        mmap: false,
+        segment_cache: 2,
+        max_readers: DEFAULT_MAX_READERS,
+        max_buffer_size: DEFAULT_MAX_BUFFER_SIZE,
+        dir_perms: None,
+        file_perms: None,
+        validate_threads: 1,
+        open_checksums: false,
+        fragment_size: None
     };
 
-    pub static ref MAX_READERS: usize = 8; 
-    pub static ref MAX_BUFFER_SIZE: usize = 8096;
+    pub static ref MAX_READERS: usize = DEFAULT_MAX_READERS;
+    pub static ref MAX_BUFFER_SIZE: usize = DEFAULT_MAX_BUFFER_SIZE;
 }
 
-/**
+// defaults of max_readers and max_buffer_size, usable in const contexts
+// unlike the MAX_READERS and MAX_BUFFER_SIZE statics
+pub const DEFAULT_MAX_READERS: usize = 8;
+pub const DEFAULT_MAX_BUFFER_SIZE: usize = 8096;
+
+/*
  * improvements allow fixed size data / data of arbitrary length
  */
 
+#[allow(dead_code, clippy::upper_case_acronyms)]
 trait WAL {
     // fn open(dir: &str, opts: Option<&Options>) -> Result<Log, Error>;
     fn close(&mut self) -> Result<(), Error>;
@@ -45,6 +87,110 @@ trait WAL {
     fn truncate_front(&mut self, index: u64) -> Result<(), Error>;
 }
 
+// Files of removed segments waiting to be reused are named RECYCLE.<name>
+const RECYCLE_PREFIX: &str = "RECYCLE.";
+
+// A binary entry is encoded as index data_size data. When the high bit of
+// data_size is set a flags byte follows it, describing the optional
+// metadata stored between data_size and data.
+const ENTRY_EXTENDED: u64 = 1 << 63;
+const ENTRY_TIMESTAMP: u8 = 0x01;
+const ENTRY_HEADERS: u8 = 0x02;
+const ENTRY_COMPRESSED: u8 = 0x04;
+const ENTRY_ENCRYPTED: u8 = 0x08;
+// An entry split into fragments is stored as consecutive records sharing
+// its index, all but the last flagged as fragments
+const ENTRY_FRAGMENT: u8 = 0x10;
+
+// An entry as stored in a segment, its data possibly compressed. The JSON
+// form keeps the field names of Entry so older JSON segments still parse.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Record {
+    index: u64,
+    data: Vec<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timestamp: Option<u64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    headers: Vec<Header>,
+    // id of the codec which compressed data
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    codec: Option<u8>,
+    // id of the key data was encrypted with, after compression
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key_id: Option<u32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    nonce: Vec<u8>,
+    // more fragments of the entry follow
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    fragment: bool,
+}
+
+// Turns a record, fragment number fragment of its entry, back into an
+// entry, decrypting and decompressing its data
+fn unpack_record(record: Record, fragment: u64, codec: Option<&Arc<dyn Codec>>, keys: Option<&Arc<dyn KeyProvider>>) -> Result<Entry, Error> {
+    let data = match record.key_id {
+        Some(key_id) => match keys {
+            Some(keys) => {
+                let aad = aad(record.index, fragment, !record.fragment, &encode_meta(&record));
+                decrypt(keys.as_ref(), key_id, &aad, &record.nonce, &record.data)?
+            },
+            None => return Err(Error::KeyNotFound),
+        },
+        None => record.data,
+    };
+    let data = match record.codec {
+        Some(id) => find_codec(id, codec)?.decompress(&data)?,
+        None => data,
+    };
+
+    Ok(Entry {
+        index: record.index,
+        data,
+        timestamp: record.timestamp,
+        headers: record.headers,
+    })
+}
+
+// Reads the fragments following first, the first fragment of an entry, and
+// returns the entry with the data of all of them. Fragments are unpacked one
+// by one. An entry continues in the following segments when reader runs out
+// before its last fragment, next moves reader on to them as described at
+// read_next_fragment.
+fn read_fragments<R: BufRead, F: FnMut(&mut R, u64) -> Result<bool, Error>>(reader: &mut R, log_format: &LogFormat, first: Record, codec: Option<&Arc<dyn Codec>>, keys: Option<&Arc<dyn KeyProvider>>, mut next: F) -> Result<Entry, Error> {
+    let mut more = first.fragment;
+    let mut entry = unpack_record(first, 0, codec, keys)?;
+    let mut fragment = 0;
+    let mut buf = Vec::new();
+    while more {
+        fragment += 1;
+        let mut record = read_next_fragment(reader, log_format, entry.index, fragment, &mut next, &mut buf)?;
+        more = record.fragment;
+        record.data = std::mem::take(&mut buf);
+        entry.data.extend_from_slice(&unpack_record(record, fragment, codec, keys)?.data);
+    }
+    Ok(entry)
+}
+
+// Reads fragment number fragment of the entry at index, its data into buf.
+// When reader runs out first, next is called with the number to move reader
+// on to where the entry continues, and returns false when it does not.
+fn read_next_fragment<R: BufRead, F: FnMut(&mut R, u64) -> Result<bool, Error>>(reader: &mut R, log_format: &LogFormat, index: u64, fragment: u64, next: &mut F, buf: &mut Vec<u8>) -> Result<Record, Error> {
+    let record = loop {
+        match read_entry_format_into(reader, log_format, buf) {
+            Err(ref e) if is_eof(e) => {
+                if !next(reader, fragment)? {
+                    return Err(Error::Corrupt);
+                }
+            },
+            result => break result?,
+        }
+    };
+    if record.index != index {
+        return Err(Error::Corrupt);
+    }
+    Ok(record)
+}
+
 #[derive(Debug)]
 pub struct Log {
     pub path: PathBuf,
@@ -53,11 +199,31 @@ pub struct Log {
     segments: Vec<Segment>,
     first_index: u64,
     last_index: u64,
-    file: BufWriter<File>,
+    writer: Arc<Mutex<Writer>>,
+    // syncs the writer in the background when durability is below High
+    syncer: Option<Syncer>,
     file_size: usize,
-    readers: Vec<Reader>
+    // time the first entry of the active segment was written
+    segment_start: u64,
+    readers: Vec<Reader>,
+    // entry offsets of recently read sealed segments
+    offsets: SegmentCache<Vec<u64>>,
+    // sealed segments mapped by read_bytes
+    maps: SegmentCache<MappedSegment>,
+    // files of removed segments kept for reuse
+    pool: Vec<PathBuf>,
+    archive: Option<Archive>,
+    // key the active segment is encrypted with
+    key_id: Option<u32>,
+    // size of the active segment's header, zero for a legacy segment
+    header_size: usize,
+    // keeps other processes from opening the log until close
+    lock: Option<LockFile>,
+    // opened by open_read_only, the writer's file is not writable
+    read_only: bool
 }
 
+
 fn load_segments(dir: &str) -> Result<(usize, usize, Vec<Segment>), Error>{
     let path = Path::new(dir);
     if !path.is_dir() {
@@ -66,10 +232,8 @@ fn load_segments(dir: &str) -> Result<(usize, usize, Vec<Segment>), Error>{
     let mut start_index = 0;
     let mut end_index = 0;
 
-    let files = fs::read_dir(dir)?;
-    let mut segments: Vec<Segment> = Vec::new();
-
-    for file in files {
+    let mut files = Vec::new();
+    for file in fs::read_dir(dir)? {
         let file = file?;
         let name = file.file_name().into_string().expect("should have valid string name");
         let file_type = file.file_type()?;
@@ -77,144 +241,656 @@ fn load_segments(dir: &str) -> Result<(usize, usize, Vec<Segment>), Error>{
             continue;
         }
 
-        let index = match u64::from_str_radix(&name[..20], 10) {
-            Err(_) => continue,
-            Ok(i) if i == 0 => continue,
+        let index = match name[..20].parse::<u64>() {
+            Err(_) | Ok(0) => continue,
             Ok(i) => i,
         };
 
-        let is_start = name.len() == 26 && name.ends_with(".START");
-        let is_end = name.len() == 24 && name.ends_with(".END");
+        // a segment continuing an entry has the number of the fragment it
+        // starts with appended to the index
+        let fragment = match &name[20..] {
+            "" | ".START" | ".END" => 0,
+            suffix if suffix.len() == 21 && suffix.starts_with('.') => match suffix[1..].parse::<u64>() {
+                Ok(fragment) if fragment > 0 => fragment,
+                _ => continue,
+            },
+            _ => continue,
+        };
+        files.push((index, fragment, name, file.path()));
+    }
+
+    // read_dir lists files in no particular order, rarely index order in
+    // a directory of thousands of segments. The segments continuing an
+    // entry follow the one it started in, and a START or END segment comes
+    // right after the segment it replaces.
+    files.sort_unstable();
 
-        if name.len() == 20 || is_start || is_end {
-            if is_start {
-                start_index = segments.len();
-            } else if is_end && end_index == 0 {
-                end_index = segments.len();
-            }
-            segments.push( Segment {
-                index,
-                path: file.path(),
-            })
+    let mut segments: Vec<Segment> = Vec::new();
+
+    for (index, fragment, name, path) in files {
+        if name.ends_with(".START") {
+            start_index = segments.len();
+        } else if name.ends_with(".END") && end_index == 0 {
+            end_index = segments.len();
         }
+        segments.push( Segment {
+            index,
+            fragment,
+            path,
+            times: None,
+            sealed: None,
+        })
     }
 
     Ok((start_index, end_index, segments))
 }
 
+// Lists the recycled segment files in dir
+fn load_pool(dir: &Path) -> Result<Vec<PathBuf>, Error> {
+    let mut pool = Vec::new();
+    for file in fs::read_dir(dir)? {
+        let file = file?;
+        if file.file_name().to_string_lossy().starts_with(RECYCLE_PREFIX) {
+            pool.push(file.path());
+        }
+    }
+    pool.sort();
+    Ok(pool)
+}
+
 fn segment_name(index: u64) -> String {
     format!("{:0>20}", index)
 }
 
-fn read_entry_binary(reader: &mut BufReader<File>, discard_data: bool) -> Result<Entry, std::io::Error>{
-    // encoded as index data_size data
-    let mut index_buf = [0; 8];
-    reader.read_exact(&mut index_buf)?; // error
+// Name of the segment file starting with fragment number fragment of the
+// entry at index, the plain segment name when it starts with the entry
+fn segment_file_name(index: u64, fragment: u64) -> String {
+    match fragment {
+        0 => segment_name(index),
+        _ => format!("{:0>20}.{:0>20}", index, fragment),
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+fn validate_headers(headers: &[Header]) -> Result<(), Error> {
+    if headers.len() > u16::MAX as usize {
+        return Err(Error::InvalidHeader);
+    }
+    for header in headers {
+        if header.key.len() > u16::MAX as usize || header.value.len() > u32::MAX as usize {
+            return Err(Error::InvalidHeader);
+        }
+    }
+    Ok(())
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> Result<u64, std::io::Error> {
+    let mut buf = [0; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32, std::io::Error> {
+    let mut buf = [0; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
 
-    let mut data_size_buf = [0; 8];
-    reader.read_exact(&mut data_size_buf)?;
+fn read_u16<R: Read>(reader: &mut R) -> Result<u16, std::io::Error> {
+    let mut buf = [0; 2];
+    reader.read_exact(&mut buf)?;
+    Ok(u16::from_be_bytes(buf))
+}
 
-    let data_size = usize::from_be_bytes(data_size_buf);
+fn read_u8<R: Read>(reader: &mut R) -> Result<u8, std::io::Error> {
+    let mut buf = [0; 1];
+    reader.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
 
-    let mut data: Vec<u8> = vec![Default::default(); data_size];
+fn read_bytes<R: Read>(reader: &mut R, size: usize) -> Result<Vec<u8>, std::io::Error> {
+    let mut data: Vec<u8> = vec![0; size];
     reader.read_exact(&mut data)?;
+    Ok(data)
+}
+
+fn encode_entry_binary(entry: &Record, buf: &mut Vec<u8>) {
+    // encoded as index data_size [flags [timestamp] [headers] [codec] [key_id nonce]] data
+    let mut flags = 0;
+    if entry.timestamp.is_some() {
+        flags |= ENTRY_TIMESTAMP;
+    }
+    if !entry.headers.is_empty() {
+        flags |= ENTRY_HEADERS;
+    }
+    if entry.codec.is_some() {
+        flags |= ENTRY_COMPRESSED;
+    }
+    if entry.key_id.is_some() {
+        flags |= ENTRY_ENCRYPTED;
+    }
+    if entry.fragment {
+        flags |= ENTRY_FRAGMENT;
+    }
+
+    let mut data_size = entry.data.len() as u64;
+    if flags != 0 {
+        data_size |= ENTRY_EXTENDED;
+    }
+
+    buf.extend_from_slice(&entry.index.to_be_bytes());
+    buf.extend_from_slice(&data_size.to_be_bytes());
 
-    Ok(Entry { index: u64::from_be_bytes(index_buf), data })
+    if flags != 0 {
+        buf.push(flags);
+    }
+    encode_meta_fields(entry, buf);
+    if let Some(key_id) = entry.key_id {
+        buf.extend_from_slice(&key_id.to_be_bytes());
+        buf.extend_from_slice(&entry.nonce);
+    }
+    buf.extend_from_slice(&entry.data);
 }
 
-fn read_entry_binary_ref(reader: &mut BufReader<&File>, discard_data: bool) -> Result<Entry, std::io::Error>{
-    // encoded as index data_size data
-    let mut index_buf = [0; 8];
-    reader.read_exact(&mut index_buf)?; // error
+// Encodes the timestamp, headers and codec of an entry the way binary
+// entries store them
+fn encode_meta_fields(entry: &Record, buf: &mut Vec<u8>) {
+    if let Some(timestamp) = entry.timestamp {
+        buf.extend_from_slice(&timestamp.to_be_bytes());
+    }
+    if !entry.headers.is_empty() {
+        buf.extend_from_slice(&(entry.headers.len() as u16).to_be_bytes());
+        for header in &entry.headers {
+            buf.extend_from_slice(&(header.key.len() as u16).to_be_bytes());
+            buf.extend_from_slice(header.key.as_bytes());
+            buf.extend_from_slice(&(header.value.len() as u32).to_be_bytes());
+            buf.extend_from_slice(&header.value);
+        }
+    }
+    if let Some(codec) = entry.codec {
+        buf.push(codec);
+    }
+}
 
-    let mut data_size_buf = [0; 8];
-    reader.read_exact(&mut data_size_buf)?;
+// Encodes the plaintext metadata of an entry authenticated by encryption,
+// as flags and fields whatever the log format. Empty when the entry has
+// none.
+fn encode_meta(entry: &Record) -> Vec<u8> {
+    let mut flags = 0;
+    if entry.timestamp.is_some() {
+        flags |= ENTRY_TIMESTAMP;
+    }
+    if !entry.headers.is_empty() {
+        flags |= ENTRY_HEADERS;
+    }
+    if entry.codec.is_some() {
+        flags |= ENTRY_COMPRESSED;
+    }
+    if flags == 0 {
+        return Vec::new();
+    }
 
-    let data_size = usize::from_be_bytes(data_size_buf);
+    let mut buf = vec![flags];
+    encode_meta_fields(entry, &mut buf);
+    buf
+}
 
-    let mut data: Vec<u8> = vec![Default::default(); data_size];
-    reader.read_exact(&mut data)?;
+fn read_entry_binary<R: Read>(reader: &mut R) -> Result<Record, Error> {
+    let (mut entry, data_size) = read_entry_meta(reader)?;
+    entry.data = read_bytes(reader, data_size)?;
+    Ok(entry)
+}
 
-    Ok(Entry { index: u64::from_be_bytes(index_buf), data })
+// Reads a binary entry up to its data, returning the record without data
+// and the size of the data that follows
+fn read_entry_meta<R: Read>(reader: &mut R) -> Result<(Record, usize), Error> {
+    let index = read_u64(reader)?;
+    read_entry_meta_after(reader, index)
 }
 
-fn read_entry_json_ref(reader: &mut BufReader<&File>) -> Result<Entry, Error> {
-    let mut buf = String::new();
-    // println!("buf {}", buf.len());
-    let read_result = reader.read_line(&mut buf);
-    // println!("{:?}", read_result);
-    match read_result {
-        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof && buf.len() > 0  => return Err(Error::Corrupt),
-        Err(e) => return Err(Error::File(e)),
-        Ok(0) => return Err(Error::File(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "empty file"))),
-        _ => {},
-    };
-    // println!("buf2 {}", buf);
-    let entry: Entry = match serde_json::from_str(&buf) {
-        Ok(entry) => entry,
-        Err(_e) =>  return Err(Error::Corrupt),
-    };
+// Like read_entry_meta, index being already read
+fn read_entry_meta_after<R: Read>(reader: &mut R, index: u64) -> Result<(Record, usize), Error> {
+    let mut data_size = read_u64(reader)?;
+    if index == 0 {
+        // zeros of preallocated space, no entry has index zero
+        return Err(Error::File(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "end of entries")));
+    }
+    let mut entry = Record { index, ..Default::default() };
+
+    if data_size & ENTRY_EXTENDED != 0 {
+        data_size &= !ENTRY_EXTENDED;
+        let flags = read_u8(reader)?;
+
+        if flags & ENTRY_TIMESTAMP != 0 {
+            entry.timestamp = Some(read_u64(reader)?);
+        }
+
+        if flags & ENTRY_HEADERS != 0 {
+            let count = read_u16(reader)?;
+            for _ in 0..count {
+                let key_size = read_u16(reader)? as usize;
+                let key = String::from_utf8(read_bytes(reader, key_size)?).map_err(|_| Error::Corrupt)?;
+                let value_size = read_u32(reader)? as usize;
+                let value = read_bytes(reader, value_size)?;
+                entry.headers.push(Header { key, value });
+            }
+        }
+
+        if flags & ENTRY_COMPRESSED != 0 {
+            entry.codec = Some(read_u8(reader)?);
+        }
+
+        if flags & ENTRY_ENCRYPTED != 0 {
+            entry.key_id = Some(read_u32(reader)?);
+            entry.nonce = read_bytes(reader, NONCE_SIZE)?;
+        }
+
+        entry.fragment = flags & ENTRY_FRAGMENT != 0;
+    }
 
-    return Ok(entry)
+    Ok((entry, data_size as usize))
 }
 
-fn read_entry_json(reader: &mut BufReader<File>, _log_format: &LogFormat) -> Result<Entry, Error> {
-    let mut buf = String::new();
-    match reader.read_line(&mut buf) {
-        Err(e) => if e.kind() == std::io::ErrorKind::UnexpectedEof && buf.len() > 0 {
-            println!("buf {}",  buf);
-            return Err(Error::Corrupt)
-        } else {
-            return Err(Error::File(e))
-        },
-        _ => {},
+// Reads size bytes into buf, replacing its contents but keeping its capacity
+fn read_data<R: Read>(reader: &mut R, size: usize, buf: &mut Vec<u8>) -> Result<(), std::io::Error> {
+    buf.clear();
+    buf.reserve(size);
+    if reader.take(size as u64).read_to_end(buf)? < size {
+        return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "entry too short"));
+    }
+    Ok(())
+}
+
+fn encode_entry_json(entry: &Record, buf: &mut Vec<u8>) {
+    serde_json::to_writer(buf, entry).expect("serialise json");
+}
+
+// Reads a line into buf, stopping in front of a zero byte as well since
+// those start the preallocated space after the last entry
+fn read_json_line<R: BufRead>(reader: &mut R, buf: &mut Vec<u8>) -> Result<usize, std::io::Error> {
+    let mut read = 0;
+    loop {
+        let available = reader.fill_buf()?;
+        if available.is_empty() {
+            return Ok(read);
+        }
+        match available.iter().position(|b| *b == b'\n' || *b == 0) {
+            Some(i) => {
+                let newline = available[i] == b'\n';
+                buf.extend_from_slice(&available[..i]);
+                reader.consume(if newline { i + 1 } else { i });
+                return Ok(read + i + newline as usize);
+            },
+            None => {
+                let n = available.len();
+                buf.extend_from_slice(available);
+                reader.consume(n);
+                read += n;
+            },
+        }
+    }
+}
+
+fn read_entry_json<R: BufRead>(reader: &mut R) -> Result<Record, Error> {
+    let mut buf = Vec::new();
+    // entries are separated by a newline written in front of them, so
+    // skip blank lines until an entry or the end of the file is reached
+    let mut read;
+    loop {
+        buf.clear();
+        read = read_json_line(reader, &mut buf)?;
+        if read == 0 {
+            return Err(Error::File(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "empty file")));
+        }
+        if !buf.iter().all(u8::is_ascii_whitespace) {
+            break;
+        }
+    }
+
+    match serde_json::from_slice(&buf) {
+        Ok(entry) => Ok(entry),
+        // only the last entry lacks the newline of the next one, so this is
+        // a write torn by a crash
+        Err(_) if read == buf.len() => Err(Error::File(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "torn entry"))),
+        Err(_) => Err(Error::Corrupt),
+    }
+}
+
+// Reads the record of an entry. Only the first fragment of an entry split
+// into fragments is returned, the others are skipped. The fragment flag of
+// the record stays set when reader ends before the last fragment, as the
+// entry continues in the next segment.
+fn read_entry_format<R: BufRead>(reader: &mut R, log_format: &LogFormat) -> Result<Record, Error> {
+    let mut entry = read_fragment(reader, log_format)?;
+    entry.fragment = !skip_fragments(reader, log_format, &entry)?;
+    Ok(entry)
+}
+
+// Reads a single record, which is a whole entry unless it was split into
+// fragments
+fn read_fragment<R: BufRead>(reader: &mut R, log_format: &LogFormat) -> Result<Record, Error> {
+    match log_format {
+        LogFormat::Binary => read_entry_binary(reader),
+        LogFormat::JSON => read_entry_json(reader)
+    }
+}
+
+// Skips the fragments following entry, reading one fragment at a time.
+// Returns false when reader ends before the last fragment.
+fn skip_fragments<R: BufRead>(reader: &mut R, log_format: &LogFormat, entry: &Record) -> Result<bool, Error> {
+    let mut more = entry.fragment;
+    let mut buf = Vec::new();
+    while more {
+        let record = match read_entry_format_into(reader, log_format, &mut buf) {
+            Err(ref e) if is_eof(e) => return Ok(false),
+            result => result?,
+        };
+        if record.index != entry.index {
+            return Err(Error::Corrupt);
+        }
+        more = record.fragment;
+    }
+    Ok(true)
+}
+
+// Skips the fragments a segment continuing the entry at index starts with,
+// fragment being the number of the first of them. Segments starting with an
+// entry, fragment zero, are left alone.
+fn skip_continued<R: BufRead>(reader: &mut R, log_format: &LogFormat, index: u64, fragment: u64) -> Result<(), Error> {
+    if fragment == 0 {
+        return Ok(());
+    }
+    match read_entry_format(reader, log_format) {
+        Ok(record) if record.index != index => Err(Error::Corrupt),
+        Ok(_) => Ok(()),
+        Err(ref e) if is_eof(e) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+// Whether segment, the last one, which continues an entry, holds the last
+// fragment of it. A crash while the entry was written can leave the segment
+// without one.
+fn finishes_entry(segment: &Segment, log_format: &LogFormat) -> Result<bool, Error> {
+    let mut reader = BufReader::new(SegmentFile::open(&segment.path, None)?);
+    SegmentHeader::read(&mut reader)?;
+    Ok(read_active_entry(&mut reader, log_format, segment.index)?.is_some_and(|record| !record.fragment))
+}
+
+// Reads the entry at index from the last segment on open, like
+// read_entry_format. The file of the last segment may have been recycled,
+// and still hold records of the segment it was before behind the entries
+// written since. Their indexes are lower than any entry of the log, so
+// None is returned at the first record not continuing the entries, as
+// it is at the end of the file, at preallocated zeros and at a write torn
+// by a crash.
+fn read_active_entry<R: BufRead + Seek>(reader: &mut R, log_format: &LogFormat, index: u64) -> Result<Option<Record>, Error> {
+    let mut entry = match read_active_record(reader, log_format, index)? {
+        Some(entry) => entry,
+        None => return Ok(None),
     };
-    // println!("buffer contents {}", buf);
-    // println!("buffer contents len {}", buf.len());
-    
-    if buf.len() == 0 || buf.len() == 1 {
-        return Err(Error::File(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "empty file")));
+    let mut more = entry.fragment;
+    while more {
+        match read_active_record(reader, log_format, index)? {
+            Some(record) => more = record.fragment,
+            None => return Ok(Some(entry)),
+        }
     }
+    entry.fragment = false;
+    Ok(Some(entry))
+}
 
-    let entry: Entry = match serde_json::from_str(&buf) {
-        Ok(entry) => entry,
-        Err(_e) =>  return Err(Error::Corrupt),
+// Reads a single record of the entry at index for read_active_entry. The
+// index of a binary record is checked before the rest of it is read, stale
+// data may start anywhere in a record. A JSON record may be followed by
+// stale data on the same line, the reader is left right behind it then.
+fn read_active_record<R: BufRead + Seek>(reader: &mut R, log_format: &LogFormat, index: u64) -> Result<Option<Record>, Error> {
+    let result = match log_format {
+        LogFormat::Binary => {
+            match read_u64(reader) {
+                Ok(read) if read == index => {},
+                Ok(_) => return Ok(None),
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(Error::File(e)),
+            }
+            read_entry_meta_after(reader, index).and_then(|(mut record, data_size)| {
+                record.data = read_bytes(reader, data_size)?;
+                Ok(record)
+            })
+        },
+        LogFormat::JSON => {
+            let mut buf = Vec::new();
+            let mut start;
+            loop {
+                start = reader.stream_position()?;
+                buf.clear();
+                if read_json_line(reader, &mut buf)? == 0 {
+                    return Ok(None);
+                }
+                if !buf.iter().all(u8::is_ascii_whitespace) {
+                    break;
+                }
+            }
+            let mut records = serde_json::Deserializer::from_slice(&buf).into_iter::<Record>();
+            match records.next() {
+                Some(Ok(record)) if record.index == index => {
+                    let end = records.byte_offset();
+                    if !buf[end..].iter().all(u8::is_ascii_whitespace) {
+                        reader.seek(SeekFrom::Start(start + end as u64))?;
+                    }
+                    Ok(record)
+                },
+                _ => return Ok(None),
+            }
+        },
     };
-    // println!("entry 2 {:?}", entry);
-    return Ok(entry)
+    match result {
+        Err(ref e) if is_eof(e) => Ok(None),
+        result => result.map(Some),
+    }
+}
+
+// Like read_fragment, but the data of the record is read into buf and the
+// record is returned without it
+fn read_entry_format_into<R: BufRead>(reader: &mut R, log_format: &LogFormat, buf: &mut Vec<u8>) -> Result<Record, Error> {
+    match log_format {
+        LogFormat::Binary => {
+            let (entry, data_size) = read_entry_meta(reader)?;
+            read_data(reader, data_size, buf)?;
+            Ok(entry)
+        },
+        LogFormat::JSON => {
+            let mut entry = read_entry_json(reader)?;
+            buf.clear();
+            buf.append(&mut entry.data);
+            Ok(entry)
+        },
+    }
+}
+
+fn is_eof(err: &Error) -> bool {
+    matches!(err, Error::File(e) if e.kind() == std::io::ErrorKind::UnexpectedEof)
+}
+
+// Fsync the directory at path so files created, renamed or removed in it
+// survive a crash
+fn sync_dir(path: &Path) -> Result<(), Error> {
+    #[cfg(unix)]
+    File::open(path)?.sync_all()?;
+    Ok(())
+}
+
+// OpenOptions giving files it creates mode, instead of the default mode
+// which the umask applies to
+fn file_options(mode: Option<u32>) -> OpenOptions {
+    let mut options = OpenOptions::new();
+    #[cfg(unix)]
+    if let Some(mode) = mode {
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, mode);
+    }
+    options
+}
+
+// Like File::create, creating the file with mode
+fn create_file(path: &Path, mode: Option<u32>) -> Result<File, std::io::Error> {
+    file_options(mode).write(true).create(true).truncate(true).open(path)
+}
+
+// Like fs::create_dir_all, creating the missing directories with mode
+fn create_dir(path: &Path, mode: Option<u32>) -> Result<(), std::io::Error> {
+    let mut builder = DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    if let Some(mode) = mode {
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, mode);
+    }
+    builder.create(path)
+}
+
+// Create the file of a new segment starting at first_index, header included
+fn create_segment(path: &Path, first_index: u64, opts: &Options, key_id: Option<u32>) -> Result<File, Error> {
+    let mut file = create_file(path, opts.file_perms)?;
+    file.write_all(&SegmentHeader::new(first_index, opts.log_format.clone(), key_id).encode())?;
+    Ok(file)
+}
+
+// Check the sealed segments, all segments but the last, on open. Every
+// header must match the segment name and the log format, and every footer
+// the index range of its segment and, with open_checksums, its data. The
+// segments are split among validate_threads threads. Returns the footer
+// of each sealed segment.
+fn validate_sealed(segments: &[Segment], opts: &Options) -> Result<Vec<Option<SegmentFooter>>, Error> {
+    let sealed = segments.len() - 1;
+    let threads = opts.validate_threads.min(sealed);
+    if threads <= 1 {
+        return (0..sealed).map(|i| validate_segment(&segments[i], segments[i + 1].first_entry(), opts)).collect();
+    }
+
+    let chunk = sealed.div_ceil(threads);
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..sealed).step_by(chunk)
+            .map(|start| scope.spawn(move || {
+                (start..sealed.min(start + chunk)).map(|i| validate_segment(&segments[i], segments[i + 1].first_entry(), opts)).collect::<Result<Vec<_>, Error>>()
+            }))
+            .collect();
+        let mut footers = Vec::with_capacity(sealed);
+        for handle in handles {
+            footers.extend(handle.join().expect("validation thread panicked")?);
+        }
+        Ok(footers)
+    })
+}
+
+fn validate_segment(segment: &Segment, next_index: u64, opts: &Options) -> Result<Option<SegmentFooter>, Error> {
+    let codec = opts.segment_compression.as_ref().map(|c| &c.codec).or(opts.compression.as_ref().map(|c| &c.codec));
+    let mut file = SegmentFile::open(&segment.path, codec)?;
+    if file.footer_rejected() {
+        return Err(Error::Corrupt);
+    }
+    if let Some(footer) = file.footer() {
+        footer.validate(segment.first_entry(), next_index)?;
+    }
+    if opts.open_checksums {
+        file.verify_checksum()?;
+    }
+    let footer = file.footer().cloned();
+    if let Some(header) = SegmentHeader::read(&mut BufReader::with_capacity(HEADER_SIZE, file))? {
+        header.validate(segment.index, &opts.log_format)?;
+    }
+    Ok(footer)
+}
+
+// Key new entries of the active segment are encrypted with: the one in its
+// header while the provider still has it, the current key otherwise
+fn active_key_id(opts: &Options, header: Option<&SegmentHeader>) -> Option<u32> {
+    let keys = opts.encryption.as_ref()?;
+    match header.and_then(|h| h.key_id) {
+        Some(key_id) if keys.key(key_id).is_some() => Some(key_id),
+        _ => Some(keys.current_key_id()),
+    }
 }
 
 impl Log {
     pub fn open(dir: &str, opts: Option<&Options>) -> Result<Log, Error>{
-        // println!("in testing ");
+        Log::open_mode(dir, opts, false)
+    }
+
+    // OpenReadOnly opens an existing log for reading only, sharing the lock
+    // with other read-only logs. Nothing in the directory is created,
+    // renamed or removed, but for the LOCK file of a log which lacks one:
+    // an interrupted truncation is only resolved in memory. Opening fails
+    // when the LOCK file can't be created, as on a read-only filesystem.
+    // Writes and truncations return Error::ReadOnly.
+    pub fn open_read_only(dir: &str, opts: Option<&Options>) -> Result<Log, Error> {
+        Log::open_mode(dir, opts, true)
+    }
+
+    fn open_mode(dir: &str, opts: Option<&Options>, read_only: bool) -> Result<Log, Error> {
         if dir == ":memory:" {
             return Err(Error::InMemoryLog);
         }
 
         let path_dir = Path::new(&dir);
         let options = opts.unwrap_or_else(|| &DEFAULT_OPTIONS);
-        // create all directory
-        create_dir_all(dir)?;
-
-        let (start_index, end_index, mut segments) = load_segments(&dir)?;
+        options.validate()?;
+        let lock = if read_only {
+            if !path_dir.is_dir() {
+                return Err(Error::NotFound);
+            }
+            Some(LockFile::shared(path_dir, options.file_perms)?)
+        } else {
+            // create all directory
+            create_dir(path_dir, options.dir_perms)?;
+            Some(LockFile::exclusive(path_dir, options.file_perms)?)
+        };
 
-        // let mut first_index = 1;
-        // let mut last_index = 0;
+        let (start_index, end_index, mut segments) = load_segments(dir)?;
+        if read_only && segments.is_empty() {
+            return Err(Error::NotFound);
+        }
 
-        // let mut file: File;
-        // println!("start index {}", start_index);
-        // println!("end index {}", end_index);
-        // println!("segments {:?}", segments);
+        let archive = match &options.archive {
+            Some(archive) => {
+                let mut archive = if read_only {
+                    Archive::open(&archive.dir)?
+                } else {
+                    Archive::create(&archive.dir, options.dir_perms, options.file_perms)?
+                };
+                if let Some(compression) = &options.segment_compression {
+                    archive.set_codec(compression.codec.clone());
+                } else if let Some(compression) = &options.compression {
+                    archive.set_codec(compression.codec.clone());
+                }
+                if let Some(keys) = &options.encryption {
+                    archive.set_keys(keys.clone());
+                }
+                Some(archive)
+            },
+            None => None,
+        };
 
-        if segments.len() == 0 {
-            let file_path = path_dir.join(&segment_name(1));
+        if segments.is_empty() {
+            let file_path = path_dir.join(segment_name(1));
             // create file
-            File::create(&file_path)?;
+            let key_id = options.encryption.as_ref().map(|keys| keys.current_key_id());
+            let file = create_segment(&file_path, 1, options, key_id)?;
+            if options.preallocate {
+                preallocate(&file, options.segment_size as u64)?;
+            }
+            if options.durability >= Durability::Medium {
+                file.sync_all()?;
+            }
 
             segments.push(
                 Segment {
                     index: 1,
-                    path: file_path
+                    fragment: 0,
+                    path: file_path,
+                    times: Some(TimeRange::empty()),
+                    sealed: None,
                 }
             );
         };
@@ -225,24 +901,29 @@ impl Log {
                 return Err(Error::Corrupt);
             }
             // Delete all files leading up to START
-            for index in 0..start_index {
-                let file_path = segments[index].path.clone();
-                fs::remove_file(file_path)?;
+            if !read_only {
+                for segment in &segments[..start_index] {
+                    fs::remove_file(&segment.path)?;
+                }
             }
 
             segments =  segments[start_index..].to_vec();
-            // rename START segment
-            let org_path = segments[0].path.to_str().expect("should have a valid path");
-            let file_name_index = org_path.len() - ".START".len();
-            // rename 
-            fs::rename(org_path, &org_path[..file_name_index])?;
-            segments[0].path = Path::new(&org_path[..file_name_index]).to_path_buf();
+            if !read_only {
+                // rename START segment
+                let org_path = segments[0].path.to_str().expect("should have a valid path");
+                let file_name_index = org_path.len() - ".START".len();
+                // rename 
+                fs::rename(org_path, &org_path[..file_name_index])?;
+                segments[0].path = Path::new(&org_path[..file_name_index]).to_path_buf();
+            }
         };
 
         if end_index != 0 {
             // Delete all files following END
-            for index in ((end_index + 1)..segments.len() - 1).rev() {
-                fs::remove_file(segments[index].path.clone())?;
+            if !read_only {
+                for index in ((end_index + 1)..segments.len() - 1).rev() {
+                    fs::remove_file(segments[index].path.clone())?;
+                }
             }
 
             segments =  segments[..end_index+1].to_vec();
@@ -253,245 +934,617 @@ impl Log {
 			    // the same starting index.
                 segments[len - 2] = segments[len - 1].clone();
                 segments.pop(); // remove last item
-                // segments = segments[..segments.len() - 1].to_vec();
             }
 
-            // rename END segment
-            let org_path = segments[segments.len() - 1].path.to_str().expect("should have a valid path");
-            let file_name_index = org_path.len() - ".END".len();
-            // rename 
-            fs::rename(org_path, &org_path[..file_name_index])?;
-            let len = segments.len();
-            segments[len - 1].path = Path::new(&org_path[..file_name_index]).to_path_buf();
+            if !read_only {
+                // rename END segment
+                let org_path = segments[segments.len() - 1].path.to_str().expect("should have a valid path");
+                let file_name_index = org_path.len() - ".END".len();
+                // rename 
+                fs::rename(org_path, &org_path[..file_name_index])?;
+                let len = segments.len();
+                segments[len - 1].path = Path::new(&org_path[..file_name_index]).to_path_buf();
+            }
         };
 
-        let first_index = segments[0].index;
-        let mut last_index = 0;
-        let last_path = segments[segments.len() - 1].path.clone();
-        let file = OpenOptions::new().read(true).write(true).open(last_path.clone())?;
-        let file_size = file.metadata()?.len();
+        if segments.windows(2).any(|w| (w[0].index, w[0].fragment) >= (w[1].index, w[1].fragment)) {
+            return Err(Error::Corrupt);
+        }
+        let footers = validate_sealed(&segments, options)?;
+        for (segment, footer) in segments.iter_mut().zip(footers) {
+            // version 1 footers record neither time
+            if let Some(footer) = footer.filter(|footer| footer.times.is_some()) {
+                segment.times = footer.times;
+                segment.sealed = Some(footer.sealed);
+            }
+        }
 
-        // read the last segment to the end of log
-        let mut reader = BufReader::new(&file);
+        // a crash while an entry was continued in new segments can leave
+        // them without its last fragment. They are dropped along with the
+        // entry, and the segment it started in is the active one again.
+        let torn = segments.len() > 1 && segments[segments.len() - 1].fragment > 0 && !finishes_entry(&segments[segments.len() - 1], &options.log_format)?;
+        if torn {
+            let index = segments[segments.len() - 1].index;
+            while segments.len() > 1 && segments[segments.len() - 1].index == index && segments[segments.len() - 1].fragment > 0 {
+                let segment = segments.pop().expect("should have segments");
+                if !read_only {
+                    fs::remove_file(&segment.path)?;
+                }
+            }
+            if !read_only {
+                let codec = options.segment_compression.as_ref().map(|c| &c.codec).or(options.compression.as_ref().map(|c| &c.codec));
+                decompress_segment(&segments[segments.len() - 1].path, codec, options.file_perms)?;
+            }
+        }
 
-        match options.log_format {
-            LogFormat::JSON => {
-                loop {
-                    match read_entry_json_ref(&mut reader) {
-                        Ok(entry) => {
-                            last_index = entry.index;
-                            continue;
-                        },
-                        Err(Error::File(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
-                        Err(e) => return Err(e),
-                    }
+        let first_index = segments[0].index;
+        // an empty last segment ends right before its first index
+        let mut last_index = segments[segments.len() - 1].index - 1;
+        let last_path = segments[segments.len() - 1].path.clone();
+        let file = OpenOptions::new().read(true).write(!read_only).open(&last_path)?;
+        // the active segment never keeps a footer, new entries go where
+        // it started. Only a footer covering the entries of this segment,
+        // whose checksum matches them, is cut off; anything else is entry
+        // data which happens to end like a footer.
+        let mut segment = SegmentFile::open(&last_path, None)?;
+        let last_first_index = segments[segments.len() - 1].first_entry();
+        let length = segment.footer()
+            .filter(|footer| footer.validate(last_first_index, footer.last_index.saturating_add(1)).is_ok())
+            .map(|footer| footer.length);
+        match length {
+            Some(length) if segment.verify_checksum().is_ok() => {
+                if !read_only {
+                    file.set_len(length)?;
                 }
             },
-            LogFormat::Binary => {
-                loop {
-                    match read_entry_binary_ref(&mut reader, true) {
-                        Ok(entry) => {
-                            last_index = entry.index;
-                            continue;
-                        },
-                        Err(e) => {
-                            if e.kind() == std::io::ErrorKind::UnexpectedEof {
-                                break;
-                            }
-                            return Err(Error::File(e));
-                        }
+            _ => segment.discard_footer(),
+        }
+
+        // read the last segment to the end of log, which comes before the
+        // end of the file when it was preallocated
+        let mut reader = BufReader::new(segment);
+        let header = SegmentHeader::read(&mut reader)?;
+        if let Some(header) = &header {
+            header.validate(segments[segments.len() - 1].index, &options.log_format)?;
+        }
+        let mut file_size = reader.stream_position()?;
+        let mut times = TimeRange::empty();
+        let mut next_index = segments[segments.len() - 1].index;
+        loop {
+            match read_active_entry(&mut reader, &options.log_format, next_index)? {
+                // an entry missing its last fragment was torn by a crash
+                Some(entry) if entry.fragment => break,
+                Some(entry) => {
+                    file_size = reader.stream_position()?;
+                    last_index = entry.index;
+                    next_index = entry.index + 1;
+                    if let Some(timestamp) = entry.timestamp {
+                        times.add(timestamp);
                     }
-                }
+                },
+                None => break,
             }
-        };
+        }
 
-        // println!("after testing",);
+        // a torn write leaves data behind the last entry, like the leading
+        // fragments of an entry whose last fragment never reached the disk,
+        // which the next entries would only partly overwrite, and so do the
+        // records a recycled segment file still holds. Preallocated space
+        // is zeros, like the leading bytes of a binary entry's index.
+        reader.seek(SeekFrom::Start(file_size))?;
+        if !read_only && reader.fill_buf()?.iter().take(8).any(|b| *b != 0) {
+            file.set_len(file_size)?;
+            if options.preallocate {
+                preallocate(&file, options.segment_size as u64)?;
+            }
+        }
+
+        let len = segments.len();
+        segments[len - 1].times = Some(times);
+        let segment_start = if times.is_empty() { now_millis() } else { times.min };
+
+        if !read_only {
+            // segments created, renamed or removed above must stay that way
+            if options.durability >= Durability::Medium {
+                sync_dir(path_dir)?;
+            }
+
+            // entries read back are on disk from here on
+            file.sync_data()?;
+        }
+        let mut writer = Writer::new(file, last_index, options.max_buffer_size)?;
 
-        let mut writer = BufWriter::new(file);
-        
         // move the write cursor to the end of 
         // the file
+        writer.file.seek(SeekFrom::Start(file_size))?;
 
-        // @TODO this should be BufWriter seek
-        // and not file seek
-        // file.seek(SeekFrom::Start(file_size))?;
-        writer.seek(SeekFrom::Start(file_size))?;
+        let writer = Arc::new(Mutex::new(writer));
+        let syncer = match &options.background_sync {
+            Some(sync) if options.durability < Durability::High && !read_only => Some(Syncer::start(writer.clone(), sync.interval)),
+            _ => None,
+        };
 
         Ok(Log {
             path: Path::new(&dir).to_path_buf(),
             opts: options.to_owned(),
             closed: false,
-            segments: segments,
+            segments,
             first_index,
             last_index,
-            file: writer,
-            // buffer: Vec::new(),
+            writer,
+            syncer,
             file_size: file_size as usize,
+            segment_start,
             readers: Vec::new(),
+            offsets: SegmentCache::new(options.segment_cache),
+            maps: SegmentCache::new(options.segment_cache),
+            pool: load_pool(path_dir)?,
+            archive,
+            key_id: active_key_id(options, header.as_ref()),
+            header_size: if header.is_some() { HEADER_SIZE } else { 0 },
+            lock,
+            read_only,
         })
     }
 
+    // Close syncs the active segment and the log directory, whatever the
+    // durability level, before closing the log
     pub fn close(&mut self) -> Result<(), Error> {
         if self.closed {
             return Err(Error::Closed);
         };
-        self.flush();
+        if let Some(mut syncer) = self.syncer.take() {
+            syncer.stop();
+        }
+        self.writer().sync()?;
+        sync_dir(&self.path)?;
         self.closed = true;
         self.segments.clear();
         self.readers.clear();
+        self.lock = None;
         Ok(())
     }
 
-    fn flush(&mut self) {
-        if self.file.buffer().len() > 0 {
-            // must write buffer or crash
-            // self.file.write_all(&self.buffer).expect("Flush: Failed to write to file");
-            // self.buffer = Vec::new();
-            self.file.flush().expect("Flush: Failed to write to file");
-            if self.opts.durability == Durability::High {
-                self.file.get_ref().sync_all().expect("Flush: Failed to sync data;");
-            }
-        };
+    // Shutdown closes the log like close, returning the index of the last
+    // entry on disk
+    pub fn shutdown(mut self) -> Result<u64, Error> {
+        self.close()?;
+        Ok(self.durable_index())
+    }
+
+    fn writer(&self) -> MutexGuard<'_, Writer> {
+        self.writer.lock().expect("writer lock")
     }
 
-    pub fn sync(&mut self) {
-        if self.file.buffer().len() > 0 {
-            // must write buffer or crash
-            // self.file.write_all(&self.buffer).expect("Flush: Failed to write to file");
-            // self.buffer = Vec::new();
-            self.file.flush().expect("Flush: Failed to write to file");
-            if self.opts.durability < Durability::High {
-                self.file.get_ref().sync_all().expect("Flush: Failed to sync data;");
+    // Write the buffered entries to the active segment, syncing them when
+    // durability is High. Entries as large as the buffer bypass it, so at
+    // High the writer syncs whenever entries were written since the last
+    // sync, buffered or not.
+    fn flush(&mut self) -> Result<(), Error> {
+        let mut writer = self.writer();
+        if self.opts.durability == Durability::High {
+            if writer.dirty() {
+                writer.sync()?;
             }
-        };
+        } else if !writer.file.buffer().is_empty() {
+            writer.file.flush()?;
+        }
+        Ok(())
     }
 
-    pub fn write<D: AsRef<[u8]>>(&mut self, index: u64, data: D) -> Result<(), Error> {
+    // Write the buffered entries to the active segment so readers see them
+    fn flush_buffer(&self) -> Result<(), Error> {
+        let mut writer = self.writer();
+        if !writer.file.buffer().is_empty() {
+            writer.file.flush()?;
+        }
+        Ok(())
+    }
+
+    // Sync writes the buffered entries to the active segment and syncs it,
+    // whatever the durability level
+    pub fn sync(&mut self) -> Result<(), Error> {
         if self.closed {
             return Err(Error::Closed);
         }
+        self.writer().sync()?;
+        Ok(())
+    }
 
-        if index != self.last_index + 1 {
-            return Err(Error::OutofOrder);
-        }
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
 
-        if self.file_size >= self.opts.segment_size {
+    // DurableIndex returns the index of the last entry synced to disk. It
+    // moves with every sync, be it a High durability write, sync, close or
+    // the background syncer.
+    pub fn durable_index(&self) -> u64 {
+        self.writer().durable_index
+    }
+
+    // WaitDurable blocks until the entry at index is synced to disk, so it
+    // can be acknowledged as persisted. With background syncing the next
+    // background sync is waited for, for up to timeout, otherwise the log
+    // is synced right away.
+    pub fn wait_durable(&self, index: u64, timeout: Duration) -> Result<(), Error> {
+        if self.closed {
+            return Err(Error::Closed);
+        }
+
+        let mut writer = self.writer();
+        if index > writer.written_index {
+            return Err(Error::OutOfRange);
+        }
+        if index <= writer.durable_index {
+            return Ok(());
+        }
+
+        if self.syncer.is_none() {
+            writer.sync()?;
+            return Ok(());
+        }
+
+        let synced = writer.synced.clone();
+        let (writer, _) = synced.wait_timeout_while(writer, timeout, |w| w.durable_index < index && w.error.is_none()).expect("writer lock");
+        if index <= writer.durable_index {
+            return Ok(());
+        }
+        match &writer.error {
+            Some(e) => Err(Error::File(std::io::Error::new(e.kind(), e.to_string()))),
+            None => Err(Error::Timeout),
+        }
+    }
+
+    // Return an error the background syncer ran into. Writes check it
+    // before appending anything, so the failed write can be retried.
+    fn sync_error(&self) -> Result<(), Error> {
+        match self.writer().error.take() {
+            Some(e) => Err(Error::File(e)),
+            None => Ok(()),
+        }
+    }
+
+    // Sync once max_bytes of background_sync were written since the last
+    // sync. The entries are written either way, so a failed sync is kept
+    // for the next write to return, like one of the background syncer.
+    fn check_sync(&mut self) {
+        if self.syncer.is_some() {
+            if let Some(max_bytes) = self.opts.background_sync.as_ref().and_then(|s| s.max_bytes) {
+                let mut writer = self.writer();
+                if writer.unsynced >= max_bytes {
+                    if let Err(e) = writer.sync() {
+                        writer.error = Some(e);
+                    }
+                }
+            }
+        }
+    }
+
+    // Fsync the log directory after segments were created, renamed or
+    // removed, unless durability is Low
+    fn sync_segments(&self) -> Result<(), Error> {
+        if self.opts.durability >= Durability::Medium {
+            sync_dir(&self.path)?;
+        }
+        Ok(())
+    }
+
+    pub fn write<D: AsRef<[u8]>>(&mut self, index: u64, data: D) -> Result<(), Error> {
+        self.write_with_headers(index, data, &[])
+    }
+
+    // Write an entry along with user headers to the log. The headers are
+    // stored next to the payload and returned by read_entry.
+    pub fn write_with_headers<D: AsRef<[u8]>>(&mut self, index: u64, data: D, headers: &[Header]) -> Result<(), Error> {
+        if self.closed {
+            return Err(Error::Closed);
+        }
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+
+        if index != self.last_index + 1 {
+            return Err(Error::OutofOrder);
+        }
+
+        validate_headers(headers)?;
+        self.sync_error()?;
+
+        if self.segment_full() {
             // cycle
-            self.cycle();
+            self.cycle()?;
         }
 
         // appendEntry
-        self.append_entry(index, data);
+        self.append_data(index, data.as_ref(), headers)?;
 
-        // check durability
-        if self.opts.durability >= Durability::Medium || self.file.buffer().len() > *MAX_BUFFER_SIZE {
-            self.flush();
+        if self.buffer_full() {
+            self.flush()?;
         }
-        // println!("index {}", index);
         self.last_index = index;
+        self.check_sync();
 
         Ok(())
     }
 
-    fn append_entry<D: AsRef<[u8]>>(&mut self, index: u64, data: D) {
-        let mark = self.file.buffer().len();
-        match self.opts.log_format {
-            LogFormat::Binary => {
+    // Appends the records of an entry. With fragment_size set, an entry with
+    // more data is split into fragments, and continues in a new segment once
+    // the active one is full. Only the first fragment carries the timestamp
+    // and headers.
+    fn append_data(&mut self, index: u64, data: &[u8], headers: &[Header]) -> Result<(), Error> {
+        let size = match self.opts.fragment_size {
+            Some(size) if data.len() > size => size,
+            _ => return self.append_entry(&self.make_record(index, 0, true, data, headers)?),
+        };
 
-                let mut index_buf = [0; 8];
-                let index_bytes = index.to_be_bytes();
-                index_buf.clone_from_slice(&index_bytes);
+        let count = data.len().div_ceil(size);
+        for (i, chunk) in data.chunks(size).enumerate() {
+            if i > 0 && self.segment_full() {
+                self.cycle_at(index, i as u64)?;
+            }
+            let last = i + 1 == count;
+            let mut record = self.make_record(index, i as u64, last, chunk, if i == 0 { headers } else { &[] })?;
+            record.fragment = !last;
+            self.append_entry(&record)?;
+        }
+        Ok(())
+    }
 
-                let data_size = data.as_ref().len();
-                let mut data_size_buf = [0; 8];
-                data_size_buf.clone_from_slice(&data_size.to_be_bytes());
+    // Builds the record appended for an entry, or fragment number fragment
+    // of it, compressing its data when it is at least min_size bytes and
+    // compression actually shrinks it, then encrypting it with the key of
+    // the active segment. Only the first fragment is stamped.
+    fn make_record(&self, index: u64, fragment: u64, last: bool, data: &[u8], headers: &[Header]) -> Result<Record, Error> {
+        let mut record = Record {
+            index,
+            data: Vec::new(),
+            timestamp: if self.opts.timestamps && fragment == 0 { Some(now_millis()) } else { None },
+            headers: headers.to_vec(),
+            ..Default::default()
+        };
 
-                self.file.write(&index_buf).expect("Failed to append entry index buf");
-                self.file.write(&data_size_buf).expect("Failed to append entry index buf");
-                self.file.write(&data.as_ref()).expect("Failed to append entry index buf");
-            },
-            LogFormat::JSON => {
-                let entry = Entry {
-                    index,
-                    data: data.as_ref().to_vec()
-                };
+        if let Some(compression) = &self.opts.compression {
+            if data.len() >= compression.min_size && compression.codec.id() != 0 {
+                let compressed = compression.codec.compress(data)?;
+                if compressed.len() < data.len() {
+                    record.data = compressed;
+                    record.codec = Some(compression.codec.id());
+                }
+            }
+        }
+        if record.codec.is_none() {
+            record.data = data.to_vec();
+        }
 
-                let json_entry = if self.file_size > 0 {
-                    // data exists
-                    format!("\n{}", serde_json::to_string(&entry).expect("serialise json"))
-                } else {
-                    format!("{}", serde_json::to_string(&entry).expect("serialise json"))
-                };
+        if let (Some(keys), Some(key_id)) = (&self.opts.encryption, self.key_id) {
+            let aad = aad(index, fragment, last, &encode_meta(&record));
+            let (nonce, data) = encrypt(keys.as_ref(), key_id, &aad, &record.data)?;
+            record.data = data;
+            record.nonce = nonce;
+            record.key_id = Some(key_id);
+        }
+        Ok(record)
+    }
 
-                let entry_bytes = json_entry.as_bytes();
-                self.file.write(&entry_bytes).expect("Failed to append entry json");
-            }
-        };
+    // Turns a record read from a segment, fragment number fragment of its
+    // entry, into an entry
+    fn unpack(&self, record: Record, fragment: u64) -> Result<Entry, Error> {
+        unpack_record(record, fragment, self.opts.compression.as_ref().map(|c| &c.codec), self.opts.encryption.as_ref())
+    }
 
-        self.file_size += self.file.buffer().len() - mark;
+    // Whether appended entries need to be flushed: always from Medium
+    // durability on, once the write buffer is full otherwise
+    fn buffer_full(&self) -> bool {
+        self.opts.durability >= Durability::Medium || self.writer().file.buffer().len() >= self.opts.max_buffer_size
     }
 
-    fn read_entry(&self, reader: &mut BufReader<File>) -> Result<Entry, Error> {
-        match self.opts.log_format {
-            LogFormat::Binary => read_entry_binary(reader, false).map_err(Error::File),
-            LogFormat::JSON => read_entry_json(reader, &self.opts.log_format)
+    // Whether the active segment holds no entries yet
+    fn segment_empty(&self) -> bool {
+        self.file_size <= self.header_size
+    }
+
+    // The active segment is sealed once it reaches segment_size, or when it
+    // holds entries older than max_segment_age.
+    fn segment_full(&self) -> bool {
+        if self.file_size >= self.opts.segment_size {
+            return true;
+        }
+        match self.opts.max_segment_age {
+            Some(max_age) => !self.segment_empty() && now_millis().saturating_sub(self.segment_start) >= max_age.as_millis() as u64,
+            None => false,
         }
     }
 
-    fn read_entry_with_index(&mut self, reader_index: usize) -> Result<Entry, Error> {
-        let reader = &mut self.readers[reader_index].rd;
+    // MaybeRotate seals the active segment when it is due, so a quiet log
+    // honours max_segment_age without waiting for the next write.
+    // Returns whether a new segment was started.
+    pub fn maybe_rotate(&mut self) -> Result<bool, Error> {
+        if self.closed {
+            return Err(Error::Closed);
+        }
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+
+        if self.segment_empty() || !self.segment_full() {
+            return Ok(false);
+        }
+
+        self.cycle()?;
+        Ok(true)
+    }
+
+    fn append_entry(&mut self, entry: &Record) -> Result<(), Error> {
+        if self.segment_empty() {
+            self.segment_start = now_millis();
+        }
+
+        let mut buf = Vec::new();
         match self.opts.log_format {
-            LogFormat::Binary => read_entry_binary(reader, false).map_err(Error::File),
-            LogFormat::JSON => read_entry_json(reader, &self.opts.log_format)
+            LogFormat::Binary => encode_entry_binary(entry, &mut buf),
+            LogFormat::JSON => {
+                if !self.segment_empty() {
+                    // data exists
+                    buf.push(b'\n');
+                }
+                encode_entry_json(entry, &mut buf);
+            }
+        };
+
+        let mut writer = self.writer();
+        writer.file.write_all(&buf)?;
+        // an entry is only written once its last fragment is
+        if !entry.fragment {
+            writer.written_index = entry.index;
+        }
+        writer.unsynced += buf.len();
+        drop(writer);
+        self.file_size += buf.len();
+
+        if let (Some(timestamp), Some(segment)) = (entry.timestamp, self.segments.last_mut()) {
+            if let Some(times) = segment.times.as_mut() {
+                times.add(timestamp);
+            }
         }
+        Ok(())
+    }
+
+    fn decode_entry<R: BufRead>(&self, reader: &mut R) -> Result<Record, Error> {
+        read_entry_format(reader, &self.opts.log_format)
     }
 
-    fn cycle(&mut self) {
-        self.flush();
-        // std::mem::drop(self.file);
-        // fn cycle(&mut self)
+    fn read_entry_with_index(&mut self, reader_index: usize, buf: &mut Vec<u8>) -> Result<Record, Error> {
+        let reader = &mut self.readers[reader_index].rd;
+        read_entry_format_into(reader, &self.opts.log_format, buf)
+    }
+
+    // Seal the active segment and start a new one. The sealed segment gets
+    // a footer recording the time range of its entries, so seek_time and
+    // retention can skip it without a scan, now and after a reopen. The new
+    // segment is encrypted with the provider's current key.
+    fn cycle(&mut self) -> Result<(), Error> {
+        self.cycle_at(self.last_index + 1, 0)
+    }
+
+    // Like cycle, the new segment starting with fragment number fragment of
+    // the entry at index. It is named after both, as the segments holding
+    // the rest of a large entry would share its index.
+    fn cycle_at(&mut self, index: u64, fragment: u64) -> Result<(), Error> {
+        // drop the preallocated space the sealed segment did not use, and
+        // sync it so its entries are durable once the new segment starts
+        let mut writer = self.writer();
+        writer.file.flush()?;
+        writer.file.get_ref().set_len(self.file_size as u64)?;
+        writer.sync()?;
+        drop(writer);
+
         let segment = Segment {
-            index: self.last_index + 1,
-            path: self.path.join(&segment_name(self.last_index + 1))
+            index,
+            fragment,
+            path: self.path.join(segment_file_name(index, fragment)),
+            times: Some(TimeRange::empty()),
+            sealed: None,
         };
 
-        let file = File::create(segment.path.clone()).expect("Cycle: Failed to create file");
-        self.file = BufWriter::new(file);
-        self.file_size = 0;
+        self.key_id = self.opts.encryption.as_ref().map(|keys| keys.current_key_id());
+        let file = match self.pool.pop() {
+            Some(recycled) => {
+                fs::rename(&recycled, &segment.path)?;
+                let mut file = OpenOptions::new().read(true).write(true).open(&segment.path)?;
+                file.write_all(&SegmentHeader::new(segment.index, self.opts.log_format.clone(), self.key_id).encode())?;
+                file
+            },
+            None => create_segment(&segment.path, segment.index, &self.opts, self.key_id)?,
+        };
+        if self.opts.preallocate {
+            preallocate(&file, self.opts.segment_size as u64)?;
+        }
+        if self.opts.durability >= Durability::Medium {
+            file.sync_all()?;
+        }
+        self.writer().replace(file)?;
+        self.file_size = HEADER_SIZE;
+        self.header_size = HEADER_SIZE;
         self.segments.push(segment);
-        // self.buffer = Vec::new();
+
+        // readers of the sealed segment would run into its footer
+        let sealed = self.segments.len() - 2;
+        self.readers.retain(|r| r.sindex as usize != sealed);
+        let sealed = &mut self.segments[sealed];
+        let footer = write_footer(&sealed.path, sealed.index, sealed.fragment, &self.opts.log_format, self.opts.footer_offsets, now_millis())?;
+        sealed.times = footer.times;
+        sealed.sealed = Some(footer.sealed);
+
+        if let Some(compression) = &self.opts.segment_compression {
+            compress_segment(&sealed.path, &compression.codec, compression.block_size, self.opts.file_perms)?;
+        }
+
+        self.apply_retention()?;
+        self.sync_segments()
+    }
+
+    // Custom codec sealed segments may have been compressed with
+    fn segment_codec(&self) -> Option<&Arc<dyn Codec>> {
+        match (&self.opts.segment_compression, &self.opts.compression) {
+            (Some(compression), _) => Some(&compression.codec),
+            (None, Some(compression)) => Some(&compression.codec),
+            (None, None) => None,
+        }
+    }
+
+    // Returns how much compressing sealed segments saved so far
+    pub fn compression_stats(&self) -> Result<CompressionStats, Error> {
+        if self.closed {
+            return Err(Error::Closed);
+        }
+
+        let mut stats = CompressionStats::default();
+        for segment in &self.segments[..self.segments.len() - 1] {
+            let file = SegmentFile::open(&segment.path, self.segment_codec())?;
+            if file.is_compressed() {
+                stats.segments += 1;
+                stats.raw_bytes += file.raw_len()?;
+                stats.stored_bytes += file.stored_len()?;
+            }
+        }
+        Ok(stats)
     }
 
     pub fn write_batch(&mut self, batch: &mut Batch) -> Result<(), Error> {
         if self.closed {
             return Err(Error::Closed);
         }
-        // println!("detecting");
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
         // check indexes
         if batch.data_sizes.iter().sum::<usize>() != batch.datas.len() {
             return Err(Error::OutofOrder);
         }
 
-        if self.file_size >= self.opts.segment_size {
-            self.cycle();
+        for headers in &batch.headers {
+            validate_headers(headers)?;
         }
+        self.sync_error()?;
 
-        // println!("{:?}", batch);
+        if self.segment_full() {
+            self.cycle()?;
+        }
 
         let mut skip = 0;
         for i in 0..batch.data_sizes.len() {
             let index = self.last_index + i as u64 + 1;
             let data = &batch.datas[skip..batch.data_sizes[i] + skip];
-            self.append_entry(index, &data);
+            let headers = batch.headers.get(i).map(|h| h.as_slice()).unwrap_or(&[]);
+            self.append_data(index, data, headers)?;
             skip += batch.data_sizes[i];
         }
-        // 8096 -> 8KB
-        // @TODO revise this implementation
-        if self.opts.durability == Durability::Medium || self.opts.durability == Durability::High || self.file.buffer().len() >= 8096 {
-            self.flush();
+        if self.buffer_full() {
+            self.flush()?;
         }
 
         self.last_index += batch.data_sizes.len() as u64;
+        self.check_sync();
         // reset the batch for reuse
         batch.clear();
 
@@ -524,6 +1577,40 @@ impl Log {
     // Read an entry from the log. This function reads an entry from disk and is
     // optimized for sequential reads. Randomly accessing entries is slow.
     pub fn read(&mut self, index: u64) -> Result<Vec<u8>, Error> {
+        self.read_entry(index).map(|entry| entry.data)
+    }
+
+    // Read an entry together with its timestamp and headers from the log.
+    pub fn read_entry(&mut self, index: u64) -> Result<Entry, Error> {
+        let mut data = Vec::new();
+        let mut record = self.read_record(index, &mut data)?;
+        record.data = data;
+        self.unpack(record, 0)
+    }
+
+    // ReadInto reads the data of an entry into buf, replacing its contents.
+    // The buffer is reused, so a loop reading entries into the same buffer
+    // only allocates when an entry outgrows it or has to be decompressed or
+    // decrypted.
+    pub fn read_into(&mut self, index: u64, buf: &mut Vec<u8>) -> Result<(), Error> {
+        let mut record = self.read_record(index, buf)?;
+        if record.codec.is_some() || record.key_id.is_some() {
+            record.data = std::mem::take(buf);
+            *buf = self.unpack(record, 0)?.data;
+        }
+        Ok(())
+    }
+
+    // IterFrom returns a cursor over the data of the entries from index on,
+    // read into a single reused buffer.
+    pub fn iter_from(&mut self, index: u64) -> DataIter<'_> {
+        DataIter { log: self, next: index, buf: Vec::new() }
+    }
+
+    // ReadStream returns a reader over the data of the entry at index. The
+    // fragments of an entry split by fragment_size are read and unpacked one
+    // at a time, so the entry never has to fit in memory as a whole.
+    pub fn read_stream(&mut self, index: u64) -> Result<EntryReader<'_>, Error> {
         if self.closed {
             return Err(Error::Closed);
         }
@@ -532,161 +1619,417 @@ impl Log {
             return Err(Error::NotFound);
         }
 
-        // find an opened reader
-        let mut r = None;
-        for i in 0..self.readers.len() {
-            if self.readers[i].nindex == index {
-                // r = Some(&mut self.readers[i]);
-                r = Some(i);
-                break;
-            }
+        let mut data = Vec::new();
+        let (sindex, reader, record) = self.seek_entry(index, &mut data)?;
+        let mut stream = EntryReader { log: self, index, sindex, reader, buf: Vec::new(), pos: 0, more: true, fragment: 0 };
+        stream.load(record, data)?;
+        Ok(stream)
+    }
+
+    // Reads the record at index, its data into buf
+    fn read_record(&mut self, index: u64, buf: &mut Vec<u8>) -> Result<Record, Error> {
+        if self.closed {
+            return Err(Error::Closed);
+        }
+
+        if index == 0 || index < self.first_index || index > self.last_index {
+            return Err(Error::NotFound);
         }
 
-        let reader_index = match r {
+        // find an opened reader
+        let reader_index = match self.readers.iter().position(|r| r.nindex == index) {
             Some(r) => r,
             // Reader not found, open a new reader and return the entry at index
-            None => return self.open_reader(index),
+            None => return self.open_reader(index, buf),
         };
         // Read next entry from reader
-        let sindex = self.readers[reader_index as usize].sindex;
-        let nindex = self.readers[reader_index as usize].nindex;
+        let sindex = self.readers[reader_index].sindex;
+        let nindex = self.readers[reader_index].nindex;
 
         loop {
-            let entry = match self.read_entry_with_index(reader_index) {
-                Err(e) => {
-                    if let Error::File(e) = e {
-                        if e.kind() == std::io::ErrorKind::UnexpectedEof {
-                            if sindex as usize == self.segments.len() - 1 {
-                                // At the ned of the last segment file
-                                if self.file.buffer().len() > 0 {
-                                    self.file.flush().expect("Failed to write to file");
-                                    // self.buffer = Vec::new();
-                                    continue;
-                                }
-                                // println!("in testing found");
-                                self.readers.remove(reader_index as usize);
-                                return Err(Error::Corrupt);
-                            }
-                            // close the old reader, open new one
-                            self.readers.remove(reader_index as usize);
-                            return self.open_reader(index);
+            let entry = match self.read_entry_with_index(reader_index, buf) {
+                Err(ref e) if is_eof(e) => {
+                    if sindex as usize == self.segments.len() - 1 {
+                        // At the ned of the last segment file
+                        self.flush_buffer()?;
+                        // entries were appended since the reader was opened
+                        let file_size = self.file_size as u64;
+                        let rd = self.readers[reader_index].rd.get_mut();
+                        if rd.limit().is_some_and(|limit| limit < file_size) {
+                            rd.set_limit(file_size);
+                            continue;
                         }
+                        self.readers.remove(reader_index);
+                        return Err(Error::Corrupt);
                     }
-                    Entry { index: 0, data: Vec::new() }
-                }  
-                Ok(e) => e.to_owned(),
+                    // close the old reader, open new one
+                    self.readers.remove(reader_index);
+                    return self.open_reader(index, buf);
+                },
+                Err(_) => Record::default(),
+                Ok(e) => e,
             };
 
-            // println!("{:?}", entry);
-            // println!("index {}", index);
-
             if entry.index != index {
-                self.readers.remove(reader_index as usize);
+                self.readers.remove(reader_index);
                 return Err(Error::Corrupt);
             }
+            // the reader moves on to the segments the entry continues in
+            let mut reader = self.readers.remove(reader_index);
+            let entry = self.join_fragments(&mut reader, entry, buf)?;
+            self.readers.insert(reader_index, reader);
+
+            self.readers[reader_index].nindex += 1;
 
-            self.readers[reader_index as usize].nindex += 1;
-            
             if nindex == self.last_index + 1 {
                 // read the last entry, close the reader
-                self.readers.remove(reader_index as usize);
+                self.readers.remove(reader_index);
+            }
+
+            return Ok(entry)
+        }
+    }
+
+    // ReadBytes reads the data of an entry like read, as a handle. With mmap
+    // set, uncompressed and unencrypted entries of sealed binary segments are
+    // borrowed from a mapping of the segment without a copy. Other entries
+    // are read into a buffer of their own.
+    pub fn read_bytes(&mut self, index: u64) -> Result<Bytes, Error> {
+        if self.closed {
+            return Err(Error::Closed);
+        }
+
+        if index == 0 || index < self.first_index || index > self.last_index {
+            return Err(Error::NotFound);
+        }
+
+        let sindex = self.find_segment(index) as usize;
+        if !self.opts.mmap || self.opts.log_format != LogFormat::Binary || self.opts.segment_compression.is_some() || sindex == self.segments.len() - 1 {
+            return self.read(index).map(Bytes::from);
+        }
+
+        let segment = match self.mapped_segment(sindex)? {
+            Some(segment) => segment,
+            None => return self.read(index).map(Bytes::from),
+        };
+        let (mut record, data) = segment.read(index)?;
+        if record.index != index {
+            return Err(Error::Corrupt);
+        }
+        // the fragments of an entry aren't contiguous in the mapping
+        if record.fragment {
+            return self.read(index).map(Bytes::from);
+        }
+        if record.codec.is_none() && record.key_id.is_none() {
+            return Ok(data);
+        }
+
+        record.data = data.to_vec();
+        self.unpack(record, 0).map(|entry| Bytes::from(entry.data))
+    }
+
+    // Mapping of the sealed segment at sindex, None when it can't be mapped
+    fn mapped_segment(&mut self, sindex: usize) -> Result<Option<&MappedSegment>, Error> {
+        let segment = &self.segments[sindex];
+        let first_index = segment.first_entry();
+        if self.maps.get(first_index).is_none() {
+            match MappedSegment::open(&segment.path, segment.index, segment.fragment, self.segment_codec())? {
+                Some(segment) => self.maps.insert(first_index, segment),
+                None => return Ok(None),
             }
+        }
+        Ok(self.maps.get(first_index))
+    }
 
-            return Ok(entry.data)
+    // Offset of the entry at index in the sealed segment at sindex. The
+    // offsets of all its entries are collected by scanning it through reader
+    // when they are not in the segment cache yet.
+    fn cached_offset(&mut self, sindex: usize, reader: &mut BufReader<SegmentFile>, index: u64) -> Result<Option<u64>, Error> {
+        if sindex == self.segments.len() - 1 {
+            return Ok(None);
         }
+
+        let first_index = self.segments[sindex].first_entry();
+        if self.offsets.get(first_index).is_none() {
+            let mut offsets = Vec::new();
+            let mut data = Vec::new();
+            loop {
+                let offset = reader.get_ref().position() - reader.buffer().len() as u64;
+                match read_entry_format_into(reader, &self.opts.log_format, &mut data) {
+                    Ok(entry) if entry.index == first_index + offsets.len() as u64 => {
+                        offsets.push(offset);
+                        // the last entry may continue in the next segment
+                        if !skip_fragments(reader, &self.opts.log_format, &entry)? {
+                            break;
+                        }
+                    },
+                    Ok(_) => return Err(Error::Corrupt),
+                    Err(ref e) if is_eof(e) => break,
+                    Err(e) => return Err(e),
+                }
+            }
+            self.offsets.insert(first_index, offsets);
+        }
+        Ok(self.offsets.get(first_index).and_then(|offsets| offsets.get((index - first_index) as usize)).copied())
+    }
+
+    // Drop the cached offsets and mappings of the segments whose first
+    // index does not satisfy keep
+    fn uncache<F: Fn(u64) -> bool>(&mut self, keep: F) {
+        self.offsets.retain(&keep);
+        self.maps.retain(&keep);
     }
 
+    // Finds the segment the entry at index starts in, which is never one of
+    // the segments continuing an entry with that index
     fn find_segment(&self, index: u64) -> u64 {
         let mut i: u64 = 0;
         let mut j = self.segments.len() as u64;
 
         while i < j {
             let h = i + (j - i) / 2;
-            if index >= self.segments[h as usize].index {
+            let segment = &self.segments[h as usize];
+            if index > segment.index || (index == segment.index && segment.fragment == 0) {
                 i = h + 1;
             } else {
                 j = h;
             }
         };
 
-        i - 1 as u64
+        i - 1
     }
 
-    fn open_reader(&mut self, index: u64) -> Result<Vec<u8>, Error> {
-        let sindex = self.find_segment(index);
-        let mut nindex = self.segments[sindex as usize].index;
-        let file = File::open(&self.segments[sindex as usize].path)?;
-        let mut buf_reader = BufReader::new(file);
+    fn open_reader(&mut self, index: u64, buf: &mut Vec<u8>) -> Result<Record, Error> {
+        let (sindex, buf_reader, entry) = self.seek_entry(index, buf)?;
+
+        // create new reader push it to the front
+        let mut reader = Reader {
+            sindex,
+            nindex: index + 1,
+            rd: buf_reader
+        };
+        let entry = self.join_fragments(&mut reader, entry, buf)?;
+
+        self.readers.insert(0, reader);
+        // loop and close readers
+        if self.readers.len() > self.opts.max_readers {
+            // close the readers that are opened
+            self.readers.truncate(self.opts.max_readers);
+        }
+
+        Ok(entry)
+    }
+
+    // Reads the other fragments of the entry whose first fragment is record,
+    // its data in buf, following the entry into the segments it continues
+    // in. buf is replaced with the unpacked data of the whole entry, and the
+    // record returned needs no unpacking then.
+    fn join_fragments(&self, reader: &mut Reader, mut record: Record, buf: &mut Vec<u8>) -> Result<Record, Error> {
+        if !record.fragment {
+            return Ok(record);
+        }
+        record.data = std::mem::take(buf);
+        let index = record.index;
+        let sindex = &mut reader.sindex;
+        let next = |rd: &mut BufReader<SegmentFile>, fragment| self.follow_fragments(sindex, rd, index, fragment);
+        let entry = read_fragments(&mut reader.rd, &self.opts.log_format, record, self.opts.compression.as_ref().map(|c| &c.codec), self.opts.encryption.as_ref(), next)?;
+        *buf = entry.data;
+        Ok(Record { index: entry.index, timestamp: entry.timestamp, headers: entry.headers, ..Default::default() })
+    }
 
-        if sindex as usize == self.segments.len() - 1 {
-            if self.file.buffer().len() > 0 {
-                // this means we are reading from the the last segment which
-                // has an in memory buffer, therefore flush the buffer
-                // before reading from file
-                // self.file.write_all(&self.buffer).expect("OpenReader: failed to write");
-                self.file.flush().expect("OpenReader: failed to write");
-                // self.buffer = Vec::new(); 
+    // Moves reader, which ran out in the segment at sindex, on to fragment
+    // number fragment of the entry at index: past the limit of the active
+    // segment when it was written since, or into the next segment when that
+    // continues the entry. Returns false when neither is the case.
+    fn follow_fragments(&self, sindex: &mut u64, reader: &mut BufReader<SegmentFile>, index: u64, fragment: u64) -> Result<bool, Error> {
+        let next = *sindex as usize + 1;
+        if next == self.segments.len() {
+            self.flush_buffer()?;
+            let file_size = self.file_size as u64;
+            let rd = reader.get_mut();
+            if rd.limit().is_some_and(|limit| limit < file_size) {
+                rd.set_limit(file_size);
+                return Ok(true);
             }
+            return Ok(false);
+        }
+
+        let segment = &self.segments[next];
+        if segment.index != index || segment.fragment != fragment {
+            return Ok(false);
+        }
+        *reader = self.continuation_reader(next)?;
+        *sindex += 1;
+        Ok(true)
+    }
+
+    // Opens a reader of the segment holding the entry at index and reads the
+    // entry's first record, its data into buf. Returns the segment index and
+    // the reader along with the record.
+    fn seek_entry(&mut self, index: u64, buf: &mut Vec<u8>) -> Result<(u64, BufReader<SegmentFile>, Record), Error> {
+        let sindex = self.find_segment(index);
+        let mut nindex = self.segments[sindex as usize].first_entry();
+        let mut buf_reader = self.segment_reader(sindex as usize)?;
+
+        // jump straight to the entry when the footer or the segment cache
+        // knows where it is
+        let offset = match buf_reader.get_mut().entry_offset(index)? {
+            Some(offset) => Some(offset),
+            None => self.cached_offset(sindex as usize, &mut buf_reader, index)?,
+        };
+        if let Some(offset) = offset {
+            buf_reader.seek(SeekFrom::Start(offset))?;
+            nindex = index;
         }
 
         // scan the file for entry at index
         loop {
-            let data = self.read_entry(&mut buf_reader)?;
-            if data.index != nindex {
+            let entry = read_entry_format_into(&mut buf_reader, &self.opts.log_format, buf)?;
+            if entry.index != nindex {
                 return Err(Error::Corrupt);
             }
 
-            nindex = data.index + 1;
+            nindex = entry.index + 1;
 
-            if data.index == index {
-                // create new reader push it to the front
-                let reader = Reader {
-                    sindex,
-                    nindex,
-                    // file,
-                    rd: buf_reader
-                };
+            if entry.index == index {
+                return Ok((sindex, buf_reader, entry));
+            }
+            if !skip_fragments(&mut buf_reader, &self.opts.log_format, &entry)? {
+                return Err(Error::Corrupt);
+            }
+        }
+    }
 
-                self.readers.insert(0, reader);
-                // loop and close readers
-                if self.readers.len() > *MAX_READERS {
-                    // close the readers that are opened
-                    self.readers.drain(self.readers.len() - 1..);
-                    // self.close_reader(&mut self.readers[self.readers.len() - 1]);
-                }
+    // SeekTime returns the index of the first entry written at or after ts,
+    // in milliseconds since the unix epoch. Entry timestamps are expected to
+    // grow with the index, which lets the segments be binary searched by
+    // their time range before a single segment is scanned. Segments without
+    // timestamps, written before timestamps were enabled, are skipped.
+    // Returns Error::InvalidOptions when timestamps are disabled.
+    pub fn seek_time(&mut self, ts: u64) -> Result<u64, Error> {
+        if self.closed {
+            return Err(Error::Closed);
+        }
+        if !self.opts.timestamps {
+            return Err(Error::InvalidOptions("seek_time needs timestamps enabled"));
+        }
+
+        if self.last_index == 0 {
+            return Err(Error::NotFound);
+        }
+
+        // found is the first segment seen so far holding an entry at or
+        // after ts, any earlier one lies in i..j
+        let mut found = self.segments.len();
+        let mut i = self.find_segment(self.first_index) as usize;
+        let mut j = found;
+
+        while i < j {
+            let h = i + (j - i) / 2;
+            // probe the first timed segment from h on, the untimed ones in
+            // between hold no entry to find
+            let mut k = h;
+            while k < j && self.segment_times(k)?.is_empty() {
+                k += 1;
+            }
+            if k < j && self.segment_times(k)?.max >= ts {
+                found = k;
+                j = h;
+            } else if k < j {
+                i = k + 1;
+            } else {
+                j = h;
             }
+        }
 
-            return Ok(data.data);
+        if found == self.segments.len() {
+            return Err(Error::NotFound);
         }
 
-        // Ok(Vec::new())
+        let mut reader = self.segment_reader(found)?;
+        loop {
+            let entry = match self.decode_entry(&mut reader) {
+                Err(ref e) if is_eof(e) => return Err(Error::NotFound),
+                Err(e) => return Err(e),
+                Ok(entry) => entry,
+            };
+
+            match entry.timestamp {
+                Some(timestamp) if timestamp >= ts && entry.index >= self.first_index => return Ok(entry.index),
+                _ => {},
+            }
+        }
     }
 
-    // pub fn close_reader(&mut self, reader: &Reader) {
-        // for i in 0..self.readers.len() {
-        //     if self.readers[i].sindex == reader.sindex && self.readers[i].nindex == reader.nindex {
-        //         self.readers[i] = self.readers[self.readers.len() - 1];
-        //         self.readers.remove(self.readers.len() - 1);
-        //         break;
-        //     }
-        // }
-    // }
+    // Returns the time range of the segment at sindex, scanning it once
+    // when neither its footer nor the writer recorded the range, as for
+    // segments sealed before footers held one.
+    fn segment_times(&mut self, sindex: usize) -> Result<TimeRange, Error> {
+        if let Some(times) = self.segments[sindex].times {
+            return Ok(times);
+        }
+
+        let mut reader = self.segment_reader(sindex)?;
+        let mut times = TimeRange::empty();
+        loop {
+            match self.decode_entry(&mut reader) {
+                Ok(entry) => {
+                    if let Some(timestamp) = entry.timestamp {
+                        times.add(timestamp);
+                    }
+                },
+                Err(ref e) if is_eof(e) => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        self.segments[sindex].times = Some(times);
+        Ok(times)
+    }
+
+    // Opens a reader at the first entry starting in the segment at sindex,
+    // past the fragments of an entry it continues
+    fn segment_reader(&self, sindex: usize) -> Result<BufReader<SegmentFile>, Error> {
+        let mut reader = self.continuation_reader(sindex)?;
+        let segment = &self.segments[sindex];
+        skip_continued(&mut reader, &self.opts.log_format, segment.index, segment.fragment)?;
+        Ok(reader)
+    }
+
+    // Opens a reader at the start of the segment at sindex, which is the
+    // rest of an entry when it continues one, after validating its header.
+    // The write buffer is flushed first when it is the last segment, whose
+    // reads stop at the last entry written.
+    fn continuation_reader(&self, sindex: usize) -> Result<BufReader<SegmentFile>, Error> {
+        let mut file = SegmentFile::open(&self.segments[sindex].path, self.segment_codec())?;
+        if sindex == self.segments.len() - 1 {
+            self.flush_buffer()?;
+            // open already dropped a footer the last segment had, what looks
+            // like one now is entry data
+            file.discard_footer();
+            file.set_limit(self.file_size as u64);
+        }
+        let mut reader = BufReader::new(file);
+        if let Some(header) = SegmentHeader::read(&mut reader)? {
+            header.validate(self.segments[sindex].index, &self.opts.log_format)?;
+        }
+        Ok(reader)
+    }
 
     pub fn truncate_back(&mut self, last_index: u64) -> Result<(), Error> {
         if self.closed {
             return Err(Error::Closed);
         }
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
         let index = last_index;
 
         if index == 0 || self.last_index == 0 || index > self.last_index || index < self.first_index {
             return Err(Error::OutOfRange);
         }
 
-        if self.file.buffer().len() > 0 {
-            // self.file.write_all(&self.buffer).expect("should write to file");
-            self.file.flush().expect("should write to file");
-            // self.buffer = Vec::new();
-        }
+        self.flush_buffer()?;
 
         // close all readers
         // TODO check do I need readers array???
@@ -696,44 +2039,42 @@ impl Log {
             return Ok(())
         }
 
-        let sindex = self.find_segment(index);
-
-        // Open file
-        let file = File::open(&self.segments[sindex as usize].path)?;
+        // the segment the entry at index ends in, the last one with an index
+        // up to it, is rewritten and those after it removed
+        let sindex = (self.segments.partition_point(|s| s.index <= index) - 1) as u64;
+        let rewritten = self.segments[sindex as usize].first_entry();
+        self.uncache(|first_index| first_index < rewritten);
 
-        // Read all entries prior to entry at index
-        let mut reader = BufReader::new(file);
-        let found;
+        // Read all entries prior to entry at index. A segment continuing
+        // the entry starts with its end, which segment_reader skipped.
+        let mut reader = self.segment_reader(sindex as usize)?;
+        let mut found = rewritten > index;
         // let mut offset = 0;
-        loop {
-            let ridx = self.read_entry(&mut reader)?;
+        while !found {
+            let ridx = self.decode_entry(&mut reader)?;
             if ridx.index == index {
                 // offset = reader.buffer().len();
                 // offset = file.seek(SeekFrom::Start(file_size))?;
                 found = true;
-                break;
-
             }
-
-        }
-
-        if !found {
-            return Err(Error::Corrupt);
         }
 
         // create a temp file in the log dir & copy all of data
         // up to offset
 
         let temp_filepath = self.path.join("TEMP");
-        let mut temp_file = File::create(&temp_filepath)?;
+        let mut temp_file = create_file(&temp_filepath, self.opts.file_perms)?;
 
         // copy read data into temp file
         // get file current position
-        let current_pos = reader.seek(SeekFrom::Current(0))?;
+        let current_pos = reader.stream_position()?;
         // move the reader to the start
         reader.seek(SeekFrom::Start(0))?;
         let mut handle = reader.take(current_pos);
         std::io::copy(&mut handle, &mut temp_file)?;
+        if self.opts.durability >= Durability::Medium {
+            temp_file.sync_all()?;
+        }
 
         drop(temp_file); // close temp_file
         // drop(file); // close file
@@ -747,37 +2088,229 @@ impl Log {
         }
 
         self.segments.truncate((sindex + 1) as usize);
+        // the time range shrank with the segment, rescan it when needed
+        self.segments[sindex as usize].times = None;
 
         fs::rename(&temp_filepath, &end_filename)?;
+        self.sync_segments()?;
+
+        self.open_writer(&end_filename)?;
+        self.last_index = index;
+
+        // the rewritten segment was only synced above Low durability
+        let durable = if self.opts.durability >= Durability::Medium { index } else { self.segments[sindex as usize].index - 1 };
+        let mut writer = self.writer();
+        writer.written_index = index;
+        writer.durable_index = writer.durable_index.min(durable);
 
-        let file = OpenOptions::new().read(true).write(true).open(end_filename.clone())?;
+        Ok(())
+    }
+
+    // Point the writer at the end of the segment file at path
+    fn open_writer(&mut self, path: &Path) -> Result<(), Error> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
         let file_size = file.metadata()?.len();
-        self.file = BufWriter::new(file);
+        let header = SegmentHeader::read(&mut BufReader::new(&file))?;
+        self.key_id = active_key_id(&self.opts, header.as_ref());
+        self.header_size = if header.is_some() { HEADER_SIZE } else { 0 };
         self.file_size = file_size as usize;
-        // self.buffer = Vec::new();
-        self.last_index = index;
 
         // move the write cursor to the end of 
         // the file
-        self.file.seek(SeekFrom::Start(file_size))?;
-        
+        let mut writer = self.writer();
+        writer.replace(file)?;
+        writer.file.seek(SeekFrom::Start(file_size))?;
+        Ok(())
+    }
+
+    // Remove the first count segments and their files from the log, moving
+    // them into the archive when one is configured. The active segment is
+    // never part of them.
+    fn remove_front_segments(&mut self, count: usize) -> Result<(), Error> {
+        self.readers.clear();
+        let first_kept = self.segments[count].first_entry();
+        self.uncache(|first_index| first_index >= first_kept);
+        for i in 0..count {
+            let last_index = self.last_started(i);
+            let segment = &self.segments[i];
+            match (self.archive.as_mut(), &self.opts.archive) {
+                (Some(archive), Some(archive_opts)) => {
+                    archive.add_file(segment, last_index, &self.opts.log_format, archive_opts.compress)?;
+                },
+                _ => {
+                    let path = segment.path.clone();
+                    self.recycle_segment(&path)?;
+                },
+            }
+        }
+        self.segments.drain(..count);
+        Ok(())
+    }
+
+    // Keep the file of a removed segment for reuse by cycle while the pool
+    // has room. Only its header is rewritten when it is reused, open stops
+    // at its old records as they don't continue the entries written since.
+    // Files that may still be mapped are only unlinked.
+    fn recycle_segment(&mut self, path: &Path) -> Result<(), Error> {
+        if self.opts.mmap || self.pool.len() >= self.opts.segment_pool {
+            fs::remove_file(path)?;
+            return Ok(());
+        }
+
+        let name = path.file_name().expect("segment should have a file name").to_string_lossy();
+        let recycled = self.path.join(format!("{}{}", RECYCLE_PREFIX, name));
+        fs::rename(path, &recycled)?;
+        self.pool.push(recycled);
+        Ok(())
+    }
+
+    // Verify reads every segment and checks its entries follow each other,
+    // and sealed segments against the checksum and index range in their
+    // footer.
+    pub fn verify(&mut self) -> Result<(), Error> {
+        if self.closed {
+            return Err(Error::Closed);
+        }
+
+        for sindex in 0..self.segments.len() {
+            let mut reader = self.segment_reader(sindex)?;
+            if reader.get_ref().footer_rejected() && sindex < self.segments.len() - 1 {
+                return Err(Error::Corrupt);
+            }
+            reader.get_mut().verify_checksum()?;
+
+            let first_index = self.segments[sindex].first_entry();
+            let next_index = match self.segments.get(sindex + 1) {
+                Some(segment) => segment.first_entry(),
+                None => self.last_index + 1,
+            };
+            if let Some(footer) = reader.get_ref().footer() {
+                footer.validate(first_index, next_index)?;
+            }
+
+            let mut index = first_index;
+            loop {
+                match self.decode_entry(&mut reader) {
+                    Ok(entry) if entry.index == index => index += 1,
+                    Ok(_) => return Err(Error::Corrupt),
+                    Err(ref e) if is_eof(e) => break,
+                    Err(e) => return Err(e),
+                }
+            }
+            if index != next_index {
+                return Err(Error::Corrupt);
+            }
+        }
+        Ok(())
+    }
+
+    // Returns the archive holding the segments dropped from the front of
+    // the log, when archiving is enabled.
+    pub fn archive(&self) -> Option<&Archive> {
+        self.archive.as_ref()
+    }
+
+    // Drop sealed segments from the front of the log until the retention
+    // policy is met. Stops at the first segment the retention hook refuses
+    // to let go of, so the log always stays contiguous. A segment goes
+    // together with the segments continuing its last entry.
+    fn apply_retention(&mut self) -> Result<(), Error> {
+        let retention = self.opts.retention.clone();
+        if retention.max_bytes.is_none() && retention.max_segments.is_none() && retention.max_age.is_none() {
+            return Ok(());
+        }
+
+        let mut sizes = Vec::new();
+        if retention.max_bytes.is_some() {
+            for segment in &self.segments {
+                sizes.push(fs::metadata(&segment.path)?.len());
+            }
+        }
+        let mut total_size: u64 = sizes.iter().sum();
+        let now = now_millis();
+
+        // the last segment is the active one and is never removed
+        let mut count = 0;
+        while count < self.segments.len() - 1 {
+            let mut end = count + 1;
+            while end < self.segments.len() && self.segments[end].fragment > 0 {
+                end += 1;
+            }
+            if end == self.segments.len() {
+                break;
+            }
+
+            let over_count = retention.max_segments.is_some_and(|max| self.segments.len() - count > max);
+            let over_size = retention.max_bytes.is_some_and(|max| total_size > max);
+            let expired = match retention.max_age {
+                Some(max_age) => now.saturating_sub(self.segment_modified(end - 1)?) >= max_age.as_millis() as u64,
+                None => false,
+            };
+
+            if !over_count && !over_size && !expired {
+                break;
+            }
+
+            if let Some(hook) = &retention.hook {
+                let first = self.segments[count].index;
+                let last = self.segments[end].index - 1;
+                if !hook.allow(first, last) {
+                    break;
+                }
+            }
+
+            if let Some(size) = sizes.get(count..end) {
+                total_size -= size.iter().sum::<u64>();
+            }
+            count = end;
+        }
+
+        if count > 0 {
+            self.remove_front_segments(count)?;
+            self.first_index = self.segments[0].index;
+        }
         Ok(())
     }
 
+    // Index of the last entry starting in the segment at sindex, which is
+    // not the active one
+    fn last_started(&self, sindex: usize) -> u64 {
+        let next = &self.segments[sindex + 1];
+        next.index - (next.fragment == 0) as u64
+    }
+
+    // Returns the time of the newest entry in the segment at sindex, or
+    // the time it was sealed when no entry has a timestamp. Both come from
+    // its footer; only segments whose footer records neither are scanned,
+    // falling back to the file modification time.
+    fn segment_modified(&mut self, sindex: usize) -> Result<u64, Error> {
+        let segment = &self.segments[sindex];
+        if let (Some(times), Some(sealed)) = (segment.times, segment.sealed) {
+            return Ok(if times.is_empty() { sealed } else { times.max });
+        }
+
+        let times = self.segment_times(sindex)?;
+        if !times.is_empty() {
+            return Ok(times.max);
+        }
+
+        let modified = fs::metadata(&self.segments[sindex].path)?.modified()?;
+        Ok(modified.duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0))
+    }
+
     pub fn truncate_front(&mut self, index: u64) -> Result<(), Error> {
         if self.closed {
             return Err(Error::Closed);
         }
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
 
         if index == 0 || self.last_index == 0 || index > self.last_index || index < self.first_index {
             return Err(Error::OutOfRange);
         }
 
-        if self.file.buffer().len() > 0 {
-            // self.file.write_all(&self.buffer).expect("should write to file");
-            self.file.flush().expect("should write to file");
-            // self.buffer = Vec::new();
-        }
+        self.flush_buffer()?;
 
         // close all readers
         // TODO check do I need readers array???
@@ -787,79 +2320,196 @@ impl Log {
             return Ok(())
         }
 
-        let sindex = self.find_segment(index);
-        // Open file
-        let file = File::open(&self.segments[sindex as usize].path)?;
-        // @TODO close file
-        let mut reader = BufReader::new(file);
+        let sindex = self.find_segment(index) as usize;
 
-        if index > self.segments[sindex as usize].index {
-            
-            let found: bool;
-            loop {
-                let ridx = self.read_entry(&mut reader)?;
+        if index > self.segments[sindex].index {
+            // Read all entries prior to entry at index, past the end of an
+            // entry the segment continues
+            let mut reader = self.segment_reader(sindex)?;
+            while index > self.segments[sindex].first_entry() {
+                let ridx = self.decode_entry(&mut reader)?;
                 if ridx.index == index - 1 {
-                    // @TODO Seeker check
-                    found = true;
                     break;
                 }
             }
-
-            if !found {
-                return Err(Error::Corrupt);
+            let prefix_size = reader.stream_position()?;
+
+            // copy the remaining entries into a temp file which becomes the
+            // new first segment, under a header of its own
+            let header = read_segment_header(&self.segments[sindex].path, self.segment_codec())?;
+            let key_id = header.and_then(|h| h.key_id).or(self.key_id);
+            let temp_filepath = self.path.join("TEMP");
+            let mut temp_file = create_segment(&temp_filepath, index, &self.opts, key_id)?;
+            std::io::copy(&mut reader, &mut temp_file)?;
+            if self.opts.durability >= Durability::Medium {
+                temp_file.sync_all()?;
             }
-        }
+            drop(temp_file);
+
+            // a START segment makes open finish the truncation if we crash
+            // before the segments in front of it are removed
+            // the rewritten segment keeps the age of the one it replaces
+            let (mut times, mut sealed) = (None, None);
+            if sindex < self.segments.len() - 1 {
+                let footer = write_footer(&temp_filepath, index, 0, &self.opts.log_format, self.opts.footer_offsets, self.segments[sindex].sealed.unwrap_or_else(now_millis))?;
+                times = footer.times;
+                sealed = Some(footer.sealed);
+            }
+            let start_filename = self.path.join(format!("{}.START", segment_name(index)));
+            fs::rename(&temp_filepath, &start_filename)?;
+            self.sync_segments()?;
 
+            self.remove_front_segments(sindex)?;
 
-        // Read all entries prior to entry at index
-        let temp_filepath = self.path.join("TEMP");
-        let mut temp_file = File::create(&temp_filepath)?;
-        // @TODO please confirm behavior
-        // reader.seek(SeekFrom::Start(index + 1)).expect("failed to seek");
+            // only the entries in front of index are archived, the rest
+            // lives on in the START segment
+            let segment = self.segments.remove(0);
+            if let (Some(archive), Some(archive_opts)) = (self.archive.as_mut(), &self.opts.archive) {
+                archive.add(&segment, prefix_size, index - 1, &self.opts.log_format, archive_opts.compress)?;
+            }
+            self.recycle_segment(&segment.path)?;
 
-        std::io::copy(&mut reader, &mut temp_file)?;
+            let end_filename = self.path.join(segment_name(index));
+            fs::rename(&start_filename, &end_filename)?;
 
-        // drop(file);
-        drop(temp_file);
+            self.segments.insert(0, Segment {
+                index,
+                path: end_filename.clone(),
+                fragment: 0,
+                times,
+                sealed
+            });
+
+            if self.segments.len() == 1 {
+                self.open_writer(&end_filename)?;
+            }
+        } else {
+            // index starts a segment, the segments in front of it can go
+            self.remove_front_segments(sindex)?;
+        }
+        self.sync_segments()?;
+        self.uncache(|first_index| first_index >= index);
 
-        let end_filename = self.path.join(segment_name(index));
-        // println!("{:?}", end_filename);
+        self.first_index = index;
+        Ok(())
+    }
+}
 
-        for i in 0..sindex+1 {
-            fs::remove_file(&self.segments[i as usize].path).expect("should remove file");
+// A log dropped without close still gets its write buffer to the OS, and
+// synced when High durability or background syncing asked for it. Errors
+// are logged as there is no one to return them to.
+impl Drop for Log {
+    fn drop(&mut self) {
+        if self.closed || self.read_only {
+            return;
+        }
+        if let Some(mut syncer) = self.syncer.take() {
+            syncer.stop();
         }
 
-        fs::rename(&temp_filepath, &end_filename)?;
-
-        self.segments.insert((sindex+1) as usize,  Segment {
-            index,
-            path: end_filename.clone()
-        });
+        let mut writer = match self.writer.lock() {
+            Ok(writer) => writer,
+            Err(_) => {
+                log::error!("wal: log {} dropped with a poisoned writer", self.path.display());
+                return;
+            },
+        };
+        let result = if self.opts.durability == Durability::High || self.opts.background_sync.is_some() {
+            writer.sync()
+        } else {
+            writer.file.flush()
+        };
+        if let Err(e) = result {
+            log::error!("wal: closing log {} on drop: {}", self.path.display(), e);
+        }
+    }
+}
 
-        if self.segments.len() == 1 {
-            let file = OpenOptions::new().read(true).write(true).open(end_filename.clone())?;
-            let file_size = file.metadata()?.len();
+// DataIter reads the data of consecutive entries into one buffer, which is
+// reused for every entry
+#[derive(Debug)]
+pub struct DataIter<'a> {
+    log: &'a mut Log,
+    next: u64,
+    buf: Vec<u8>,
+}
 
-            self.file = BufWriter::new(file);
-            self.file_size = file_size as usize;
-            // self.buffer = Vec::new();
+impl DataIter<'_> {
+    // ReadNext returns the index and data of the next entry, None once the last
+    // entry of the log was read. The data is only valid until the following
+    // call.
+    pub fn read_next(&mut self) -> Result<Option<(u64, &[u8])>, Error> {
+        if self.next > self.log.last_index {
+            return Ok(None);
         }
+        let index = self.next;
+        self.log.read_into(index, &mut self.buf)?;
+        self.next += 1;
+        Ok(Some((index, &self.buf)))
+    }
+}
 
-        self.first_index = index;
+// EntryReader streams the data of a single entry, a fragment at a time
+#[derive(Debug)]
+pub struct EntryReader<'a> {
+    log: &'a Log,
+    index: u64,
+    // segment reader is reading, the entry continues in the following ones
+    sindex: u64,
+    reader: BufReader<SegmentFile>,
+    // unpacked data of the current fragment, read up to pos
+    buf: Vec<u8>,
+    pos: usize,
+    // more fragments follow the current one
+    more: bool,
+    // number of the current fragment
+    fragment: u64,
+}
+
+impl EntryReader<'_> {
+    // Makes record, with its data in data, the current fragment
+    fn load(&mut self, mut record: Record, data: Vec<u8>) -> Result<(), Error> {
+        if record.index != self.index {
+            return Err(Error::Corrupt);
+        }
+        self.more = record.fragment;
+        record.data = data;
+        self.buf = self.log.unpack(record, self.fragment)?.data;
+        self.pos = 0;
         Ok(())
     }
 }
 
+impl Read for EntryReader<'_> {
+    fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos == self.buf.len() && self.more {
+            let mut data = std::mem::take(&mut self.buf);
+            self.fragment += 1;
+            let (log, sindex, index) = (self.log, &mut self.sindex, self.index);
+            let mut next = |reader: &mut BufReader<SegmentFile>, fragment| log.follow_fragments(sindex, reader, index, fragment);
+            let record = read_next_fragment(&mut self.reader, &log.opts.log_format, index, self.fragment, &mut next, &mut data)?;
+            self.load(record, data)?;
+        }
+
+        let n = out.len().min(self.buf.len() - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
 
 #[cfg(test)]
 mod test {
     use super::*;
     use super::primitives::Options;
+    use crate::codec::Codec;
     use crate::error::*;
     use crate::Batch;
     use std::fs;
+    use std::ops::Range;
+    use std::path::PathBuf;
     use std::str;
-    use std::path::Path;
+    use tempfile::TempDir;
 
 
     #[test]
@@ -875,7 +2525,1638 @@ mod test {
         let path = format!("{}{}", base_path, "/json");
         test_log(&path, 100, Some(&make_options(512, Durability::Low, LogFormat::JSON)));
 
-        fs::remove_dir_all(&base_path).expect("should remove dir");
+        fs::remove_dir_all(base_path).expect("should remove dir");
+    }
+
+    #[test]
+    fn entry_metadata() {
+        let dir = test_dir();
+
+        for (name, log_format) in &[("json", LogFormat::JSON), ("binary", LogFormat::Binary)] {
+            let path = log_path(&dir, name);
+            let mut opts = make_options(256, Durability::Medium, log_format.clone());
+            opts.timestamps = true;
+            let mut log = Log::open(&path, Some(&opts)).expect("should open log");
+
+            for i in 1..51 {
+                let headers = vec![Header::new("route", format!("r-{}", i % 3)), Header::new("n", i.to_string())];
+                log.write_with_headers(i, data_str(i), &headers).expect("should write entry with headers");
+            }
+            log.write(51, data_str(51)).expect("should write entry without headers");
+
+            let mut log = reopen(log, &opts);
+            for i in (1..51).rev() {
+                let entry = log.read_entry(i).expect("should read entry");
+                assert_eq!(entry.index, i);
+                assert_eq!(entry.data, data_str(i).into_bytes());
+                assert!(entry.timestamp.is_some(), "entry {} should have a timestamp", i);
+                assert_eq!(entry.headers.len(), 2);
+                assert_eq!(entry.header("route"), Some(format!("r-{}", i % 3).as_bytes()));
+                assert_eq!(entry.header("n"), Some(i.to_string().as_bytes()));
+            }
+
+            let entry = log.read_entry(51).expect("should read entry");
+            assert!(entry.headers.is_empty());
+            assert_eq!(entry.data, data_str(51).into_bytes());
+
+            // headers are kept by batch writes too
+            let mut batch = Batch::new();
+            batch.write_with_headers(data_str(52), &[Header::new("batch", "yes")]);
+            batch.write(data_str(53));
+            log.write_batch(&mut batch).expect("should write batch");
+            assert_eq!(log.read_entry(52).expect("should read entry").header("batch"), Some(&b"yes"[..]));
+            assert!(log.read_entry(53).expect("should read entry").headers.is_empty());
+        }
+
+        // entries are not stamped when timestamps are disabled
+        let path = log_path(&dir, "untimed");
+        let mut opts = make_options(256, Durability::Medium, LogFormat::Binary);
+        opts.timestamps = false;
+        let mut log = Log::open(&path, Some(&opts)).expect("should open log");
+        log.write(1, data_str(1)).expect("should write entry");
+        assert_eq!(log.read_entry(1).expect("should read entry").timestamp, None);
+    }
+
+    #[test]
+    fn seek_time() {
+        let dir = test_dir();
+        let path = log_path(&dir, "seektime");
+        let mut opts = make_options(256, Durability::Low, LogFormat::Binary);
+        opts.timestamps = true;
+        let mut log = Log::open(&path, Some(&opts)).expect("should open log");
+
+        // SeekTime -- empty log
+        match log.seek_time(0) {
+            Err(Error::NotFound) => {},
+            _ => panic!("SeekTime: expected NotFound on empty log")
+        };
+
+        let n = 100;
+        for i in 1..n+1 {
+            log.write(i, data_str(i)).expect("should write entry");
+            if i % 10 == 0 {
+                std::thread::sleep(std::time::Duration::from_millis(2));
+            }
+        }
+
+        let check = |log: &mut Log| {
+            for i in 1..n+1 {
+                let ts = log.read_entry(i).expect("should read entry").timestamp.expect("should have timestamp");
+                let found = log.seek_time(ts).expect("should seek time");
+                assert!(found <= i, "SeekTime: expected index <= {}, got {}", i, found);
+                let found_ts = log.read_entry(found).expect("should read entry").timestamp.unwrap();
+                assert!(found_ts >= ts);
+                if found > 1 {
+                    let prev_ts = log.read_entry(found - 1).expect("should read entry").timestamp.unwrap();
+                    assert!(prev_ts < ts, "SeekTime: entry {} is not the first at {}", found, ts);
+                }
+            }
+
+            assert_eq!(log.seek_time(0).expect("should seek time"), 1);
+            match log.seek_time(u64::MAX) {
+                Err(Error::NotFound) => {},
+                _ => panic!("SeekTime: expected NotFound past the last entry")
+            };
+        };
+
+        check(&mut log);
+
+        // sealed segments from a previous run keep their time range in the
+        // footer, no scan is needed to find them
+        let mut log = reopen(log, &opts);
+        let sealed = log.segments.len() - 1;
+        assert!(sealed > 1);
+        for segment in &log.segments[..sealed] {
+            let times = segment.times.expect("footer should hold the time range");
+            assert!(!times.is_empty() && times.min <= times.max);
+        }
+        check(&mut log);
+        log.close().expect("should close log");
+
+        // SeekTime -- timestamps disabled
+        opts.timestamps = false;
+        let mut log = Log::open(&path, Some(&opts)).expect("should re-open log");
+        match log.seek_time(0) {
+            Err(Error::InvalidOptions(_)) => {},
+            other => panic!("SeekTime: expected InvalidOptions, got {:?}", other)
+        };
+
+        // SeekTime -- segments written before timestamps were enabled are
+        // skipped, wherever the search lands
+        write_entries(&mut log, n+1..2*n+1);
+        opts.timestamps = true;
+        let mut log = reopen(log, &opts);
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        for i in 2*n+1..3*n+1 {
+            log.write(i, data_str(i)).expect("should write entry");
+        }
+        assert!(log.segments.iter().filter(|s| s.times.is_some_and(|t| t.is_empty())).count() > 2);
+        let ts = log.read_entry(2*n+1).expect("should read entry").timestamp.unwrap();
+        let last_ts = log.read_entry(n).expect("should read entry").timestamp.unwrap();
+        assert_eq!(log.seek_time(last_ts + 1).expect("should seek time"), 2*n+1);
+        assert_eq!(log.seek_time(ts).expect("should seek time"), 2*n+1);
+        assert_eq!(log.seek_time(0).expect("should seek time"), 1);
+        for i in 2*n+1..3*n+1 {
+            let ts = log.read_entry(i).expect("should read entry").timestamp.unwrap();
+            let found = log.seek_time(ts).expect("should seek time");
+            assert!(found <= i && found > 2*n, "SeekTime: expected index in {}..={}, got {}", 2*n+1, i, found);
+        }
+        check(&mut log);
+    }
+
+    // Forget the log like a process crash would, its write buffer is lost
+    // and its lock released
+    fn crash(mut log: Log) {
+        drop(log.lock.take());
+        std::mem::forget(log);
+    }
+
+    // Directory a test keeps its logs in, removed when the test ends
+    // however it ends
+    fn test_dir() -> TempDir {
+        tempfile::Builder::new().prefix("wal-").tempdir().expect("should create test dir")
+    }
+
+    // Path of the log called name in dir
+    fn log_path(dir: &TempDir, name: &str) -> String {
+        dir.path().join(name).to_str().expect("test dir should be utf-8").to_string()
+    }
+
+    // Writes the entries in range, each with the data of data_str
+    fn write_entries(log: &mut Log, range: Range<u64>) {
+        for i in range {
+            log.write(i, data_str(i)).expect("should write entry");
+        }
+    }
+
+    // Closes log and opens it again with opts
+    fn reopen(mut log: Log, opts: &Options) -> Log {
+        log.close().expect("should close log");
+        Log::open(&log.path.to_string_lossy(), Some(opts)).expect("should re-open log")
+    }
+
+    fn segment_count<P: AsRef<Path>>(path: P) -> usize {
+        fs::read_dir(path).expect("should read dir")
+            .filter(|f| f.as_ref().expect("should read entry").file_name().len() == 20)
+            .count()
+    }
+
+    fn test_readable(log: &mut Log, first: u64, last: u64) {
+        test_first_last(log, first, last);
+        for i in first..last+1 {
+            let data = log.read(i).expect("Read: should read entry");
+            assert_eq!(data, data_str(i).into_bytes());
+        }
+        match log.read(first - 1) {
+            Err(Error::NotFound) => {},
+            _ => panic!("Read: expected NotFound in front of the log")
+        };
+    }
+
+    #[test]
+    fn retention() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicU64, Ordering};
+
+        let dir = test_dir();
+
+        // Retention -- segment count
+        let path = log_path(&dir, "count");
+        let mut opts = make_options(128, Durability::Low, LogFormat::Binary);
+        opts.retention.max_segments = Some(3);
+        let mut log = Log::open(&path, Some(&opts)).expect("should open log");
+        write_entries(&mut log, 1..201);
+        assert_eq!(segment_count(&path), 3);
+        let first = log.firstindex().expect("should return first index");
+        assert!(first > 1);
+        test_readable(&mut log, first, 200);
+        let mut log = reopen(log, &opts);
+        test_readable(&mut log, first, 200);
+
+        // Retention -- total size
+        let path = log_path(&dir, "size");
+        let mut opts = make_options(128, Durability::Low, LogFormat::JSON);
+        opts.retention.max_bytes = Some(1024);
+        let mut log = Log::open(&path, Some(&opts)).expect("should open log");
+        write_entries(&mut log, 1..201);
+        log.sync().expect("should sync");
+        let total: u64 = fs::read_dir(&path).expect("should read dir")
+            .map(|f| f.expect("should read entry").metadata().expect("should stat").len())
+            .sum();
+        // the limit is checked when the new segment is still empty
+        assert!(total <= 1024 + 256, "Retention: {} bytes left", total);
+        let first = log.firstindex().expect("should return first index");
+        assert!(first > 1);
+        test_readable(&mut log, first, 200);
+
+        // Retention -- age, every sealed segment is expired right away
+        let path = log_path(&dir, "age");
+        let mut opts = make_options(128, Durability::Low, LogFormat::Binary);
+        opts.retention.max_age = Some(std::time::Duration::from_millis(0));
+        let mut log = Log::open(&path, Some(&opts)).expect("should open log");
+        write_entries(&mut log, 1..201);
+        assert_eq!(segment_count(&path), 1);
+        let first = log.firstindex().expect("should return first index");
+        test_readable(&mut log, first, 200);
+
+        // Retention -- age after a reopen goes by the seal time in the
+        // footer, not by the modification time copying resets
+        let path = log_path(&dir, "age_reopen");
+        let mut opts = make_options(128, Durability::Low, LogFormat::Binary);
+        let mut log = Log::open(&path, Some(&opts)).expect("should open log");
+        write_entries(&mut log, 1..101);
+        let active = log.segments[log.segments.len() - 1].index;
+        log.close().expect("should close log");
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        for f in fs::read_dir(&path).expect("should read dir") {
+            let file = File::options().write(true).open(f.expect("should read entry").path()).expect("should open file");
+            file.set_modified(std::time::SystemTime::now()).expect("should set modification time");
+        }
+        opts.retention.max_age = Some(std::time::Duration::from_millis(100));
+        let mut log = Log::open(&path, Some(&opts)).expect("should re-open log");
+        assert!(log.segments[0].sealed.is_some());
+        write_entries(&mut log, 101..121);
+        let first = log.firstindex().expect("should return first index");
+        assert!(first >= active, "Retention: first index {} kept past max_age", first);
+        test_readable(&mut log, first, 120);
+
+        // Retention -- hook keeps segments that are not acknowledged
+        let path = log_path(&dir, "hook");
+        let acked = Arc::new(AtomicU64::new(0));
+        let hook_acked = acked.clone();
+        let mut opts = make_options(128, Durability::Low, LogFormat::Binary);
+        opts.retention.max_segments = Some(2);
+        opts.retention.hook = Some(RetentionHook::new(move |_first, last| last <= hook_acked.load(Ordering::SeqCst)));
+        let mut log = Log::open(&path, Some(&opts)).expect("should open log");
+        write_entries(&mut log, 1..101);
+        test_readable(&mut log, 1, 100);
+
+        acked.store(50, Ordering::SeqCst);
+        write_entries(&mut log, 101..201);
+        let first = log.firstindex().expect("should return first index");
+        assert!(first > 1 && first <= 51, "Retention: first index {} past the acknowledged entries", first);
+        test_readable(&mut log, first, 200);
+    }
+
+    #[test]
+    fn archive() {
+        use crate::codec::Deflate;
+        use std::sync::Arc;
+
+        let dir = test_dir();
+
+        // gzipping block compressed segments archives their original bytes
+        for (name, compress, log_format, blocks) in &[("plain", false, LogFormat::Binary, false), ("gzip", true, LogFormat::JSON, false), ("blocks", true, LogFormat::Binary, true)] {
+            let path = log_path(&dir, &format!("{}/log", name));
+            let archive_path = log_path(&dir, &format!("{}/archive", name));
+            let mut opts = make_options(128, Durability::Low, log_format.clone());
+            opts.retention.max_segments = Some(2);
+            if *blocks {
+                opts.segment_size = 1024;
+                opts.segment_compression = Some(SegmentCompressionOptions {
+                    codec: Arc::new(Deflate::default()),
+                    block_size: 256,
+                });
+            }
+            opts.archive = Some(ArchiveOptions {
+                dir: PathBuf::from(&archive_path),
+                compress: *compress,
+            });
+
+            let mut log = Log::open(&path, Some(&opts)).expect("should open log");
+            write_entries(&mut log, 1..201);
+
+            // Archive -- retention moved the sealed segments
+            let first = log.firstindex().expect("should return first index");
+            assert!(first > 1);
+            let archive = log.archive().expect("should have an archive");
+            assert_eq!(archive.first_index(), 1);
+            assert_eq!(archive.last_index(), first - 1);
+            for i in 1..first {
+                assert_eq!(archive.read(i).expect("should read archived entry"), data_str(i).into_bytes());
+            }
+            match archive.read(first) {
+                Err(Error::NotFound) => {},
+                _ => panic!("Archive: expected NotFound for a live entry")
+            };
+            test_readable(&mut log, first, 200);
+
+            // Archive -- truncating into the middle of a segment archives the
+            // entries in front of the index only
+            log.truncate_front(first + 1).expect("should truncate front");
+            test_readable(&mut log, first + 1, 200);
+            let archive = log.archive().expect("should have an archive");
+            assert_eq!(archive.last_index(), first);
+            log.close().expect("should close log");
+
+            // Archive -- read back through a standalone archive
+            let archive = Archive::open(&archive_path).expect("should open archive");
+            assert_eq!(archive.first_index(), 1);
+            assert_eq!(archive.last_index(), first);
+            for i in 1..first+1 {
+                let entry = archive.read_entry(i).expect("should read archived entry");
+                assert_eq!(entry.index, i);
+                assert_eq!(entry.data, data_str(i).into_bytes());
+            }
+        }
+    }
+
+    #[test]
+    fn time_rotation() {
+        use std::thread::sleep;
+        use std::time::Duration;
+
+        let dir = test_dir();
+        let path = log_path(&dir, "rotation");
+        let mut opts = make_options(1024 * 1024, Durability::Medium, LogFormat::Binary);
+        opts.max_segment_age = Some(Duration::from_millis(50));
+        opts.timestamps = true;
+        let mut log = Log::open(&path, Some(&opts)).expect("should open log");
+
+        // MaybeRotate -- nothing to rotate in an empty segment
+        assert!(!log.maybe_rotate().expect("should check rotation"));
+
+        write_entries(&mut log, 1..11);
+        assert!(!log.maybe_rotate().expect("should check rotation"));
+        assert_eq!(segment_count(&path), 1);
+
+        // Write -- rotates once the segment is old enough
+        sleep(Duration::from_millis(60));
+        log.write(11, data_str(11)).expect("should write entry");
+        assert_eq!(segment_count(&path), 2);
+
+        // MaybeRotate -- rotates a quiet log
+        sleep(Duration::from_millis(60));
+        assert!(log.maybe_rotate().expect("should check rotation"));
+        assert!(!log.maybe_rotate().expect("should check rotation"));
+        assert_eq!(segment_count(&path), 3);
+        let sealed_at = now_millis();
+
+        // the sealed segments keep their time range
+        assert_eq!(log.seek_time(0).expect("should seek time"), 1);
+        let ts = log.read_entry(11).expect("should read entry").timestamp.unwrap();
+        assert_eq!(log.seek_time(ts).expect("should seek time"), 11);
+        match log.seek_time(sealed_at + 1) {
+            Err(Error::NotFound) => {},
+            _ => panic!("SeekTime: expected NotFound after the sealed segments")
+        };
+
+        // Open -- an empty active segment after rotation
+        let mut log = reopen(log, &opts);
+        test_readable(&mut log, 1, 11);
+        log.write(12, data_str(12)).expect("should write entry");
+        test_readable(&mut log, 1, 12);
+    }
+
+    fn json_doc(i: u64) -> String {
+        format!("{{\"id\":{},\"name\":\"document\",\"tags\":[\"a\",\"b\",\"c\"],\"body\":\"{}\"}}", i, "lorem ipsum ".repeat(20))
+    }
+
+    fn dir_size<P: AsRef<Path>>(path: P) -> u64 {
+        fs::read_dir(path).expect("should read dir")
+            .map(|f| f.expect("should read entry").metadata().expect("should stat").len())
+            .sum()
+    }
+
+    // Reverses the data and drops the opening brace of the json documents,
+    // which keeps it smaller than the input, to check codecs outside the
+    // built-in ones
+    #[derive(Debug)]
+    struct Reverse;
+
+    impl Codec for Reverse {
+        fn id(&self) -> u8 {
+            200
+        }
+
+        fn compress(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+            let mut data = data.to_vec();
+            data.reverse();
+            data.pop();
+            Ok(data)
+        }
+
+        fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+            let mut data = data.to_vec();
+            data.reverse();
+            data.insert(0, b'{');
+            Ok(data)
+        }
+    }
+
+    #[test]
+    fn compression() {
+        use crate::codec::Deflate;
+        use std::sync::Arc;
+
+        let dir = test_dir();
+
+        for (name, log_format) in &[("json", LogFormat::JSON), ("binary", LogFormat::Binary)] {
+            let plain_path = log_path(&dir, &format!("{}/plain", name));
+            let path = log_path(&dir, &format!("{}/deflate", name));
+            let plain_opts = make_options(4096, Durability::Low, log_format.clone());
+            let mut opts = plain_opts.clone();
+            opts.compression = Some(CompressionOptions {
+                codec: Arc::new(Deflate::default()),
+                min_size: 64,
+            });
+
+            let mut plain = Log::open(&plain_path, Some(&plain_opts)).expect("should open log");
+            let mut log = Log::open(&path, Some(&opts)).expect("should open log");
+            for i in 1..101 {
+                // every other entry is below min_size
+                let data = if i % 2 == 0 { json_doc(i) } else { data_str(i) };
+                plain.write(i, &data).expect("should write entry");
+                log.write(i, &data).expect("should write entry");
+            }
+            plain.close().expect("should close log");
+            log.close().expect("should close log");
+            assert!(dir_size(&path) * 2 < dir_size(&plain_path), "Compression: log did not shrink");
+
+            // Compression -- mixed log, compressed entries stay readable
+            // once compression is switched off
+            let mut log = Log::open(&path, Some(&plain_opts)).expect("should re-open log");
+            for i in 101..111 {
+                log.write(i, json_doc(i)).expect("should write entry");
+            }
+            for i in 1..111 {
+                let data = if i % 2 == 0 || i > 100 { json_doc(i) } else { data_str(i) };
+                assert_eq!(log.read(i).expect("should read entry"), data.into_bytes());
+            }
+            log.close().expect("should close log");
+        }
+
+        // Compression -- custom codec
+        let path = log_path(&dir, "custom");
+        let mut opts = make_options(4096, Durability::Low, LogFormat::Binary);
+        opts.compression = Some(CompressionOptions {
+            codec: Arc::new(Reverse),
+            min_size: 0,
+        });
+        let mut log = Log::open(&path, Some(&opts)).expect("should open log");
+        for i in 1..11 {
+            log.write(i, json_doc(i)).expect("should write entry");
+        }
+        for i in 1..11 {
+            assert_eq!(log.read(i).expect("should read entry"), json_doc(i).into_bytes());
+        }
+        let mut log = reopen(log, &make_options(4096, Durability::Low, LogFormat::Binary));
+        match log.read(1) {
+            Err(Error::UnknownCodec) => {},
+            _ => panic!("Read: expected UnknownCodec without the custom codec")
+        };
+    }
+
+    #[test]
+    fn segment_compression() {
+        use crate::codec::Deflate;
+        use std::sync::Arc;
+
+        let dir = test_dir();
+        let path = log_path(&dir, "segment_compression");
+        let mut opts = make_options(2048, Durability::Low, LogFormat::JSON);
+        opts.timestamps = true;
+        opts.segment_compression = Some(SegmentCompressionOptions {
+            codec: Arc::new(Deflate::default()),
+            block_size: 512,
+        });
+
+        let mut log = Log::open(&path, Some(&opts)).expect("should open log");
+        for i in 1..201 {
+            log.write(i, json_doc(i)).expect("should write entry");
+        }
+
+        let stats = log.compression_stats().expect("should return stats");
+        assert_eq!(stats.segments, segment_count(&path) - 1);
+        assert!(stats.segments > 1);
+        assert!(stats.ratio() < 0.5, "SegmentCompression: ratio {}", stats.ratio());
+
+        // Read -- random reads from compressed segments
+        for i in (1..201).rev() {
+            assert_eq!(log.read(i).expect("should read entry"), json_doc(i).into_bytes());
+        }
+
+        let mut log = reopen(log, &opts);
+        for i in 1..201 {
+            assert_eq!(log.read(i).expect("should read entry"), json_doc(i).into_bytes());
+        }
+        assert_eq!(log.seek_time(0).expect("should seek time"), 1);
+
+        // Truncate -- into the middle of compressed segments
+        log.truncate_front(30).expect("should truncate front");
+        log.truncate_back(100).expect("should truncate back");
+        for i in 30..101 {
+            assert_eq!(log.read(i).expect("should read entry"), json_doc(i).into_bytes());
+        }
+        for i in 101..151 {
+            log.write(i, json_doc(i)).expect("should write entry");
+        }
+
+        let mut log = reopen(log, &opts);
+        test_first_last(&log, 30, 150);
+        for i in 30..151 {
+            assert_eq!(log.read(i).expect("should read entry"), json_doc(i).into_bytes());
+        }
+    }
+
+    #[test]
+    fn encryption() {
+        use crate::crypto::StaticKeys;
+        use std::sync::Arc;
+
+        let dir = test_dir();
+        let path = log_path(&dir, "encryption");
+        let keys = Arc::new(StaticKeys::new(1, [1; 32]));
+        let mut opts = make_options(1024, Durability::Medium, LogFormat::Binary);
+        opts.encryption = Some(keys.clone());
+
+        let mut log = Log::open(&path, Some(&opts)).expect("should open log");
+        write_entries(&mut log, 1..51);
+        // rotate -- segments created from now on use key 2
+        keys.insert(2, [2; 32]);
+        keys.set_current(2);
+        let rotated = log.lastindex().expect("should return last index") + 1;
+        write_entries(&mut log, 51..101);
+        test_readable(&mut log, 1, 100);
+        log.close().expect("should close log");
+
+        // the payload never reaches the disk in the clear
+        for entry in fs::read_dir(&path).expect("should read dir") {
+            let contents = fs::read(entry.expect("should read entry").path()).expect("should read segment");
+            assert!(!contents.windows(7).any(|w| w == b"data-10"));
+        }
+
+        // entries after the rotation still need key 1 up to the next segment
+        let last = Log::open(&path, Some(&opts)).expect("should re-open log").segments.last().expect("should have segments").index;
+        assert!(last > rotated);
+        let mut opts2 = opts.clone();
+        opts2.encryption = Some(Arc::new(StaticKeys::new(2, [2; 32])));
+        let mut log = Log::open(&path, Some(&opts2)).expect("should re-open log");
+        match log.read(1) {
+            Err(Error::KeyNotFound) => {},
+            other => panic!("Encryption: expected KeyNotFound, got {:?}", other),
+        }
+        for i in last..101 {
+            assert_eq!(log.read(i).expect("should read entry"), data_str(i).into_bytes());
+        }
+        log.close().expect("should close log");
+
+        // flip a byte of the last entry's authentication tag
+        let active = fs::read_dir(&path).expect("should read dir")
+            .map(|e| e.expect("should read entry").path())
+            .filter(|p| p.file_name().is_some_and(|name| name.len() == 20))
+            .max().expect("should have segments");
+        let mut contents = fs::read(&active).expect("should read segment");
+        let len = contents.len();
+        contents[len - 1] ^= 0xff;
+        fs::write(&active, contents).expect("should write segment");
+
+        let mut log = Log::open(&path, Some(&opts)).expect("should re-open log");
+        for i in 1..100 {
+            assert_eq!(log.read(i).expect("should read entry"), data_str(i).into_bytes());
+        }
+        match log.read(100) {
+            Err(Error::AuthenticationFailed) => {},
+            other => panic!("Encryption: expected AuthenticationFailed, got {:?}", other),
+        }
+        log.close().expect("should close log");
+
+        // fragments are bound to their place in the entry: swapping two, or
+        // cutting off the last and clearing the flag of the one before it,
+        // fails authentication
+        let fragments_path = log_path(&dir, "fragments");
+        let mut opts = make_options(1024 * 1024, Durability::Medium, LogFormat::JSON);
+        opts.encryption = Some(keys.clone());
+        opts.fragment_size = Some(100);
+        let blob: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        let mut log = Log::open(&fragments_path, Some(&opts)).expect("should open log");
+        log.write(1, data_str(1)).expect("should write entry");
+        log.write(2, &blob).expect("should write entry");
+        let segment = log.segments[0].path.clone();
+        log.close().expect("should close log");
+
+        let contents = fs::read(&segment).expect("should read segment");
+        let (header, body) = contents.split_at(HEADER_SIZE);
+        let lines: Vec<&[u8]> = body.split_inclusive(|&b| b == b'\n').collect();
+        let swapped = [&lines[..3], &[lines[4], lines[3]], &lines[5..]].concat().concat();
+        let flag = b",\"fragment\":true}";
+        let pos = lines[9].windows(flag.len()).position(|w| w == flag).expect("should have a fragment flag");
+        let cut = [&lines[..9], &[&lines[9][..pos], b"}\n"]].concat().concat();
+        for tampered in [swapped, cut] {
+            fs::write(&segment, [header, &tampered].concat()).expect("should write segment");
+            let mut log = Log::open(&fragments_path, Some(&opts)).expect("should re-open log");
+            assert_eq!(log.read(1).expect("should read entry"), data_str(1).into_bytes());
+            match log.read(2) {
+                Err(Error::AuthenticationFailed) => {},
+                other => panic!("Encryption: expected AuthenticationFailed, got {:?}", other),
+            }
+            log.close().expect("should close log");
+        }
+
+        // the timestamp, headers and codec stay readable, but changing them
+        // fails authentication as well
+        use crate::codec::Deflate;
+        let meta_path = log_path(&dir, "meta");
+        let mut opts = make_options(1024 * 1024, Durability::Medium, LogFormat::JSON);
+        opts.encryption = Some(keys.clone());
+        opts.timestamps = true;
+        opts.compression = Some(CompressionOptions { codec: Arc::new(Deflate::default()), min_size: 0 });
+        let mut log = Log::open(&meta_path, Some(&opts)).expect("should open log");
+        log.write_with_headers(1, vec![b'a'; 200], &[Header { key: "kind".to_string(), value: b"blob".to_vec() }]).expect("should write entry");
+        log.write(2, data_str(2)).expect("should write entry");
+        let segment = log.segments[0].path.clone();
+        log.close().expect("should close log");
+
+        let contents = fs::read(&segment).expect("should read segment");
+        let (header, body) = contents.split_at(HEADER_SIZE);
+        let (first, rest) = body.split_at(body.iter().position(|&b| b == b'\n').expect("should have two entries"));
+        let entry: serde_json::Value = serde_json::from_slice(first).expect("should parse entry");
+        assert!(entry["codec"].is_u64() && entry["timestamp"].is_u64());
+        let tampers: [fn(&mut serde_json::Value); 4] = [
+            |e| e["timestamp"] = (e["timestamp"].as_u64().unwrap() + 1).into(),
+            |e| e["headers"][0]["value"] = b"blog".to_vec().into(),
+            |e| { e.as_object_mut().unwrap().remove("headers"); },
+            |e| { e.as_object_mut().unwrap().remove("codec"); },
+        ];
+        for tamper in tampers {
+            let mut tampered = entry.clone();
+            tamper(&mut tampered);
+            fs::write(&segment, [header, &serde_json::to_vec(&tampered).unwrap(), rest].concat()).expect("should write segment");
+            let mut log = Log::open(&meta_path, Some(&opts)).expect("should re-open log");
+            assert_eq!(log.read(2).expect("should read entry"), data_str(2).into_bytes());
+            match log.read_entry(1) {
+                Err(Error::AuthenticationFailed) => {},
+                other => panic!("Encryption: expected AuthenticationFailed, got {:?}", other),
+            }
+            log.close().expect("should close log");
+        }
+        fs::write(&segment, &contents).expect("should write segment");
+        let mut log = Log::open(&meta_path, Some(&opts)).expect("should re-open log");
+        let entry = log.read_entry(1).expect("should read entry");
+        assert_eq!(entry.data, vec![b'a'; 200]);
+        assert_eq!(entry.headers[0].value, b"blob".to_vec());
+    }
+
+    #[test]
+    fn segment_header() {
+        let dir = test_dir();
+        let path = log_path(&dir, "segment_header");
+        let opts = make_options(1024, Durability::Medium, LogFormat::Binary);
+
+        let mut log = Log::open(&path, Some(&opts)).expect("should open log");
+        write_entries(&mut log, 1..101);
+        for segment in &log.segments {
+            let header = read_segment_header(&segment.path, None).expect("should read header").expect("should have a header");
+            assert_eq!(header.first_index, segment.index);
+            assert_eq!(header.log_format, LogFormat::Binary);
+            assert_eq!(header.version, segment::HEADER_VERSION);
+        }
+
+        // Truncate -- the new first segment gets a header of its own
+        log.truncate_front(30).expect("should truncate front");
+        let header = read_segment_header(&log.segments[0].path, None).expect("should read header").expect("should have a header");
+        assert_eq!(header.first_index, 30);
+        log.close().expect("should close log");
+
+        // Format -- segments written as Binary are not read as JSON
+        let json = make_options(1024, Durability::Medium, LogFormat::JSON);
+        match Log::open(&path, Some(&json)) {
+            Err(Error::FormatMismatch) => {},
+            other => panic!("SegmentHeader: expected FormatMismatch, got {:?}", other.map(|_| ())),
+        }
+
+        // Legacy -- segments without a header are still read and appended to
+        let path = log_path(&dir, "legacy");
+        fs::create_dir_all(&path).expect("should create dir");
+        let mut buf = Vec::new();
+        for i in 1..11 {
+            encode_entry_binary(&Record { index: i, data: data_str(i).into_bytes(), ..Default::default() }, &mut buf);
+        }
+        fs::write(Path::new(&path).join(segment_name(1)), &buf).expect("should write legacy segment");
+
+        let mut log = Log::open(&path, Some(&opts)).expect("should open legacy log");
+        assert_eq!(read_segment_header(&log.segments[0].path, None).expect("should read header"), None);
+        write_entries(&mut log, 11..101);
+        test_readable(&mut log, 1, 100);
+
+        let mut log = reopen(log, &opts);
+        test_readable(&mut log, 1, 100);
+    }
+
+    #[test]
+    fn segment_footer() {
+        let dir = test_dir();
+        for (name, log_format) in &[("binary", LogFormat::Binary), ("json", LogFormat::JSON)] {
+            let path = log_path(&dir, name);
+            let mut opts = make_options(1024, Durability::Medium, log_format.clone());
+            opts.footer_offsets = true;
+
+            let mut log = Log::open(&path, Some(&opts)).expect("should open log");
+            write_entries(&mut log, 1..201);
+
+            // sealed segments carry a footer, the active one does not
+            let segments = log.segments.clone();
+            for (i, segment) in segments.iter().enumerate() {
+                let file = SegmentFile::open(&segment.path, None).expect("should open segment");
+                match segments.get(i + 1) {
+                    Some(next) => {
+                        let footer = file.footer().expect("should have a footer");
+                        assert_eq!(footer.first_index, segment.index);
+                        assert_eq!(footer.last_index, next.index - 1);
+                        assert_eq!(footer.count, next.index - segment.index);
+                        assert!(footer.has_offsets);
+                    },
+                    None => assert!(file.footer().is_none()),
+                }
+            }
+
+            // Read -- random reads jump to the entry through the offsets
+            for i in (1..201).rev() {
+                assert_eq!(log.read(i).expect("should read entry"), data_str(i).into_bytes());
+            }
+            log.verify().expect("should verify");
+
+            let mut log = reopen(log, &opts);
+            test_readable(&mut log, 1, 200);
+            log.close().expect("should close log");
+
+            // Corrupt -- a flipped byte fails the checksum of its segment
+            let sealed = &segments[1].path;
+            let mut contents = fs::read(sealed).expect("should read segment");
+            contents[HEADER_SIZE + 4] ^= 0xff;
+            fs::write(sealed, contents).expect("should write segment");
+
+            let mut log = Log::open(&path, Some(&opts)).expect("should re-open log");
+            match log.verify() {
+                Err(Error::Corrupt) => {},
+                other => panic!("SegmentFooter: expected Corrupt, got {:?}", other),
+            }
+        }
+
+        // Open -- entry data ending the active segment like a footer is kept,
+        // be it without a crc or covering other entries
+        let path = log_path(&dir, "lookalike");
+        let opts = make_options(4096, Durability::Medium, LogFormat::Binary);
+        let mut log = Log::open(&path, Some(&opts)).expect("should open log");
+        write_entries(&mut log, 1..11);
+        for (i, first_index, crc) in &[(11, 1, false), (12, 2, true)] {
+            // the footer would start right after the index and size of the
+            // entry holding it
+            let length = fs::metadata(&log.segments[0].path).expect("should stat segment").len() + 16;
+            let footer = SegmentFooter { count: *i, first_index: *first_index, last_index: first_index + i - 1, length, checksum: 0, has_offsets: false, times: None, sealed: 0 };
+            let mut data = footer.encode(&[]);
+            if !crc {
+                let crc_at = data.len() - 12;
+                data.drain(crc_at..crc_at + 4);
+            }
+            log.write(*i, &data).expect("should write entry");
+            log = reopen(log, &opts);
+            test_first_last(&log, 1, *i);
+            assert_eq!(log.read(*i).expect("should read entry"), data);
+        }
+        log.write(13, data_str(13)).expect("should write entry");
+        assert_eq!(log.read(13).expect("should read entry"), data_str(13).into_bytes());
+        log.close().expect("should close log");
+    }
+
+    #[test]
+    fn preallocation() {
+        let dir = test_dir();
+        for (name, log_format) in &[("binary", LogFormat::Binary), ("json", LogFormat::JSON)] {
+            let path = log_path(&dir, name);
+            let mut opts = make_options(1024, Durability::Medium, log_format.clone());
+            opts.preallocate = true;
+            opts.segment_pool = 2;
+            opts.retention.max_segments = Some(3);
+
+            let mut log = Log::open(&path, Some(&opts)).expect("should open log");
+            for i in 1..11 {
+                log.write(i, data_str(i)).expect("should write entry");
+                assert_eq!(log.read(i).expect("should read entry"), data_str(i).into_bytes());
+            }
+            // the active segment is allocated in full
+            let active = log.segments.last().expect("should have segments").path.clone();
+            assert_eq!(fs::metadata(&active).expect("should stat segment").len(), 1024);
+
+            // Reopen -- appends continue at the last entry, not the file end
+            let mut log = reopen(log, &opts);
+            test_readable(&mut log, 1, 10);
+            write_entries(&mut log, 11..201);
+            let first = log.firstindex().expect("should return first index");
+            assert!(first > 1);
+            for i in first..201 {
+                assert_eq!(log.read(i).expect("should read entry"), data_str(i).into_bytes());
+            }
+            // sealed segments drop the unused space and end with their footer
+            for segment in &log.segments[..log.segments.len() - 1] {
+                assert!(SegmentFile::open(&segment.path, None).expect("should open segment").footer().is_some());
+            }
+
+            // each cycle takes a file from the pool before retention hands
+            // the removed segment back
+            let pooled = || fs::read_dir(&path).expect("should read dir")
+                .filter(|e| e.as_ref().expect("should read entry").file_name().to_string_lossy().starts_with(RECYCLE_PREFIX))
+                .count();
+            assert_eq!(pooled(), 1);
+            log.verify().expect("should verify");
+
+            // the pool holds at most segment_pool files
+            let mut opts = opts.clone();
+            opts.retention.max_segments = None;
+            log.opts = opts.clone();
+            write_entries(&mut log, 201..401);
+            log.truncate_front(390).expect("should truncate front");
+            assert_eq!(pooled(), 2);
+
+            let mut log = reopen(log, &opts);
+            test_first_last(&log, 390, 400);
+            for i in 390..401 {
+                assert_eq!(log.read(i).expect("should read entry"), data_str(i).into_bytes());
+            }
+            log.write(401, data_str(401)).expect("should write entry");
+            assert_eq!(log.read(401).expect("should read entry"), data_str(401).into_bytes());
+            log.close().expect("should close log");
+
+            // Recycle -- a reused file keeps the records it had behind the
+            // entries written since, open stops at them
+            let path = log_path(&dir, &format!("{}_recycle", name));
+            let mut opts = make_options(1024, Durability::Medium, log_format.clone());
+            opts.segment_pool = 1;
+            opts.retention.max_segments = Some(2);
+            let data = |i: u64| format!("{}{}", data_str(i), "x".repeat((i % 13) as usize));
+            let mut log = Log::open(&path, Some(&opts)).expect("should open log");
+            let mut i = 1;
+            // up to the first entry of a new segment, which reuses a file
+            loop {
+                log.write(i, data(i)).expect("should write entry");
+                if i > 100 && log.segments[1].index == i {
+                    break;
+                }
+                i += 1;
+            }
+            for j in i + 1..i + 3 {
+                log.write(j, data(j)).expect("should write entry");
+            }
+            let last = i + 2;
+            let active = log.segments[1].path.clone();
+            assert!(fs::metadata(&active).expect("should stat segment").len() > log.file_size as u64);
+            crash(log);
+
+            let mut log = Log::open(&path, Some(&opts)).expect("should re-open log");
+            assert_eq!(log.lastindex().expect("should return last index"), last);
+            let first = log.firstindex().expect("should return first index");
+            for j in first..last + 1 {
+                assert_eq!(log.read(j).expect("should read entry"), data(j).into_bytes());
+            }
+            log.write(last + 1, data(last + 1)).expect("should write entry");
+            let mut log = reopen(log, &opts);
+            assert_eq!(log.lastindex().expect("should return last index"), last + 1);
+            assert_eq!(log.read(last + 1).expect("should read entry"), data(last + 1).into_bytes());
+            log.verify().expect("should verify");
+            log.close().expect("should close log");
+        }
+    }
+
+    #[test]
+    fn durability() {
+        let dir = test_dir();
+        // crashing drops the write buffer of the log
+        for (name, durability) in &[("low", Durability::Low), ("medium", Durability::Medium), ("high", Durability::High)] {
+            let path = log_path(&dir, name);
+            let opts = make_options(1024, durability.clone(), LogFormat::Binary);
+
+            let mut log = Log::open(&path, Some(&opts)).expect("should open log");
+            write_entries(&mut log, 1..11);
+            crash(log);
+
+            let mut log = Log::open(&path, Some(&opts)).expect("should re-open log");
+            let expect_last = if *durability == Durability::Low { 0 } else { 10 };
+            assert_eq!(log.lastindex().expect("should return last index"), expect_last, "Durability: {:?}", durability);
+
+            // Sync -- syncs at every level
+            write_entries(&mut log, expect_last + 1..expect_last + 11);
+            log.sync().expect("should sync");
+            crash(log);
+
+            let mut log = Log::open(&path, Some(&opts)).expect("should re-open log");
+            test_readable(&mut log, 1, expect_last + 10);
+            log.close().expect("should close log");
+            match log.sync() {
+                Err(Error::Closed) => {},
+                other => panic!("Durability: expected Closed, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn background_sync() {
+        use std::time::{Duration, Instant};
+
+        let dir = test_dir();
+        let path = log_path(&dir, "background_sync");
+        let mut opts = make_options(1024, Durability::Low, LogFormat::Binary);
+        opts.background_sync = Some(SyncOptions { interval: Duration::from_millis(10), max_bytes: None });
+
+        // Interval -- buffered entries are synced without another write
+        let mut log = Log::open(&path, Some(&opts)).expect("should open log");
+        write_entries(&mut log, 1..11);
+        let start = Instant::now();
+        while log.durable_index() < 10 {
+            assert!(start.elapsed() < Duration::from_secs(5), "BackgroundSync: durable index stuck at {}", log.durable_index());
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        crash(log);
+
+        let mut log = Log::open(&path, Some(&opts)).expect("should re-open log");
+        test_readable(&mut log, 1, 10);
+        assert_eq!(log.durable_index(), 10);
+        log.close().expect("should close log");
+
+        // Bytes -- a write past max_bytes syncs before it returns
+        opts.background_sync = Some(SyncOptions { interval: Duration::from_secs(3600), max_bytes: Some(1) });
+        let mut log = Log::open(&path, Some(&opts)).expect("should re-open log");
+        for i in 11..101 {
+            log.write(i, data_str(i)).expect("should write entry");
+            assert_eq!(log.durable_index(), i);
+        }
+
+        // Error -- a failed background sync fails the next write before it
+        // appends anything, so the write can be retried
+        log.writer().error = Some(std::io::Error::other("sync failed"));
+        assert!(matches!(log.write(101, data_str(101)), Err(Error::File(_))));
+        assert_eq!(log.lastindex().expect("should return last index"), 100);
+        log.write(101, data_str(101)).expect("should write entry");
+        log.writer().error = Some(std::io::Error::other("sync failed"));
+        let mut batch = Batch::new();
+        batch.write(data_str(102));
+        assert!(matches!(log.write_batch(&mut batch), Err(Error::File(_))));
+        assert_eq!(log.lastindex().expect("should return last index"), 101);
+        log.write_batch(&mut batch).expect("should write batch");
+        test_readable(&mut log, 1, 102);
+        log.close().expect("should close log");
+
+        // without background syncing Low durability syncs on sync only
+        let opts = make_options(1024, Durability::Low, LogFormat::Binary);
+        let mut log = Log::open(&path, Some(&opts)).expect("should re-open log");
+        log.write(103, data_str(103)).expect("should write entry");
+        assert_eq!(log.durable_index(), 102);
+        log.sync().expect("should sync");
+        assert_eq!(log.durable_index(), 103);
+        log.close().expect("should close log");
+    }
+
+    #[test]
+    fn wait_durable() {
+        use std::time::Duration;
+
+        let dir = test_dir();
+        let path = log_path(&dir, "wait_durable");
+
+        // without background syncing the wait syncs the log itself
+        let opts = make_options(1024, Durability::Medium, LogFormat::Binary);
+        let mut log = Log::open(&path, Some(&opts)).expect("should open log");
+        write_entries(&mut log, 1..11);
+        assert_eq!(log.durable_index(), 0);
+        log.wait_durable(5, Duration::from_secs(1)).expect("should be durable");
+        assert_eq!(log.durable_index(), 10);
+        match log.wait_durable(11, Duration::from_secs(1)) {
+            Err(Error::OutOfRange) => {},
+            other => panic!("WaitDurable: expected OutOfRange, got {:?}", other),
+        }
+        log.close().expect("should close log");
+
+        // with background syncing the wait lasts until the next sync
+        let mut opts = make_options(1024, Durability::Low, LogFormat::Binary);
+        opts.background_sync = Some(SyncOptions { interval: Duration::from_millis(20), max_bytes: None });
+        let mut log = Log::open(&path, Some(&opts)).expect("should re-open log");
+        write_entries(&mut log, 11..21);
+        log.wait_durable(20, Duration::from_secs(5)).expect("should be durable");
+        assert_eq!(log.durable_index(), 20);
+        log.close().expect("should close log");
+
+        opts.background_sync = Some(SyncOptions { interval: Duration::from_secs(3600), max_bytes: None });
+        let mut log = Log::open(&path, Some(&opts)).expect("should re-open log");
+        log.write(21, data_str(21)).expect("should write entry");
+        match log.wait_durable(21, Duration::from_millis(20)) {
+            Err(Error::Timeout) => {},
+            other => panic!("WaitDurable: expected Timeout, got {:?}", other),
+        }
+        log.close().expect("should close log");
+
+        // High durability syncs every write
+        let opts = make_options(1024, Durability::High, LogFormat::Binary);
+        let mut log = Log::open(&path, Some(&opts)).expect("should re-open log");
+        log.write(22, data_str(22)).expect("should write entry");
+        assert_eq!(log.durable_index(), 22);
+        log.wait_durable(22, Duration::from_millis(1)).expect("should be durable");
+
+        // an entry larger than the buffer bypasses it and is synced all the same
+        log.write(23, vec![b'x'; opts.max_buffer_size * 2]).expect("should write entry");
+        assert_eq!(log.durable_index(), 23);
+        log.wait_durable(23, Duration::from_millis(1)).expect("should be durable");
+        let mut batch = Batch::new();
+        batch.write(vec![b'y'; opts.max_buffer_size * 2]);
+        batch.write(data_str(25));
+        log.write_batch(&mut batch).expect("should write batch");
+        assert_eq!(log.durable_index(), 25);
+        log.close().expect("should close log");
+    }
+
+    #[test]
+    fn mmap() {
+        use crate::codec::Deflate;
+
+        let dir = test_dir();
+        let path = log_path(&dir, "mmap");
+        let mut opts = make_options(1024, Durability::Medium, LogFormat::Binary);
+        opts.mmap = true;
+        opts.segment_pool = 2;
+
+        let mut log = Log::open(&path, Some(&opts)).expect("should open log");
+        write_entries(&mut log, 1..101);
+
+        // sealed segments are read in place, the active one through a buffer
+        let active = log.segments.last().expect("should have segments").index;
+        for i in 1..101 {
+            let data = log.read_bytes(i).expect("should read entry");
+            assert_eq!(&*data, data_str(i).as_bytes());
+            assert_eq!(data.is_mapped(), i < active);
+        }
+        assert!(log.maps.len() <= opts.segment_cache);
+
+        // a handle outlives the removal of its segment, whose file is not
+        // recycled
+        let first = log.read_bytes(1).expect("should read entry");
+        log.truncate_front(active).expect("should truncate front");
+        assert_eq!(&*first, data_str(1).as_bytes());
+        assert!(log.pool.is_empty());
+        log.close().expect("should close log");
+
+        // compressed entries are unpacked into a buffer, with footer offsets
+        // or without
+        let data = |i: u64| if i.is_multiple_of(2) { data_str(i).repeat(20) } else { data_str(i) };
+        for footer_offsets in &[false, true] {
+            let mut opts = opts.clone();
+            opts.footer_offsets = *footer_offsets;
+            opts.compression = Some(CompressionOptions { codec: Arc::new(Deflate::default()), min_size: 64 });
+            let path = log_path(&dir, &format!("compressed_{}", footer_offsets));
+            let mut log = Log::open(&path, Some(&opts)).expect("should open log");
+            for i in 1..101 {
+                log.write(i, data(i)).expect("should write entry");
+            }
+            let active = log.segments.last().expect("should have segments").index;
+            for i in (1..101).rev() {
+                let bytes = log.read_bytes(i).expect("should read entry");
+                assert_eq!(&*bytes, data(i).as_bytes());
+                assert_eq!(bytes.is_mapped(), !i.is_multiple_of(2) && i < active);
+            }
+            log.close().expect("should close log");
+        }
+    }
+
+    #[test]
+    fn read_into() {
+        use crate::codec::Deflate;
+
+        let dir = test_dir();
+        let data = |i: u64| if i.is_multiple_of(3) { data_str(i).repeat(20) } else { data_str(i) };
+        for (name, log_format) in &[("binary", LogFormat::Binary), ("json", LogFormat::JSON)] {
+            let path = log_path(&dir, name);
+            let mut opts = make_options(1024, Durability::Low, log_format.clone());
+            opts.compression = Some(CompressionOptions { codec: Arc::new(Deflate::default()), min_size: 64 });
+
+            let mut log = Log::open(&path, Some(&opts)).expect("should open log");
+            for i in 1..201 {
+                log.write(i, data(i)).expect("should write entry");
+            }
+
+            // the buffer keeps its capacity across plain entries
+            let mut buf = Vec::with_capacity(256);
+            let ptr = buf.as_ptr();
+            for i in (1..201u64).filter(|i| !i.is_multiple_of(3)) {
+                log.read_into(i, &mut buf).expect("should read entry");
+                assert_eq!(buf, data(i).into_bytes());
+                assert_eq!(buf.as_ptr(), ptr);
+            }
+            for i in 1..201 {
+                log.read_into(i, &mut buf).expect("should read entry");
+                assert_eq!(buf, data(i).into_bytes());
+            }
+            match log.read_into(201, &mut buf) {
+                Err(Error::NotFound) => {},
+                other => panic!("ReadInto: expected NotFound, got {:?}", other),
+            }
+
+            let mut iter = log.iter_from(50);
+            let mut next = 50;
+            while let Some((index, bytes)) = iter.read_next().expect("should read entry") {
+                assert_eq!(index, next);
+                assert_eq!(bytes, data(index).as_bytes());
+                next += 1;
+            }
+            assert_eq!(next, 201);
+            log.close().expect("should close log");
+        }
+    }
+
+    #[test]
+    fn segment_cache() {
+        let dir = test_dir();
+        for (name, log_format) in &[("binary", LogFormat::Binary), ("json", LogFormat::JSON)] {
+            let path = log_path(&dir, name);
+            let opts = make_options(1024, Durability::Low, log_format.clone());
+
+            let mut log = Log::open(&path, Some(&opts)).expect("should open log");
+            write_entries(&mut log, 1..301);
+            let starts: Vec<u64> = log.segments.iter().map(|s| s.index).collect();
+            assert!(starts.len() > 4);
+
+            // random reads across hot segments are served from their offsets
+            for _ in 0..3 {
+                for i in (starts[0]..starts[2]).rev().step_by(7) {
+                    assert_eq!(log.read(i).expect("should read entry"), data_str(i).into_bytes());
+                }
+            }
+            assert_eq!(log.offsets.len(), 2);
+            assert!(log.offsets.get(starts[0]).is_some() && log.offsets.get(starts[1]).is_some());
+
+            // the least recently used segment makes room for a new one
+            let i = starts[3] + 2;
+            assert_eq!(log.read(i).expect("should read entry"), data_str(i).into_bytes());
+            assert_eq!(log.offsets.len(), 2);
+            assert!(log.offsets.get(starts[0]).is_none());
+
+            // truncating drops only the offsets of rewritten or removed segments
+            log.truncate_front(starts[1] + 1).expect("should truncate front");
+            assert!(log.offsets.get(starts[1]).is_none() && log.offsets.get(starts[3]).is_some());
+            log.truncate_back(starts[3] + 3).expect("should truncate back");
+            assert_eq!(log.offsets.len(), 0);
+            test_readable(&mut log, starts[1] + 1, starts[3] + 3);
+            for i in (starts[1] + 1..starts[3] + 4).rev() {
+                assert_eq!(log.read(i).expect("should read entry"), data_str(i).into_bytes());
+            }
+            log.close().expect("should close log");
+        }
+
+        // a cache has to hold at least the segment in use
+        let mut opts = make_options(1024, Durability::Low, LogFormat::Binary);
+        opts.segment_cache = 0;
+        assert!(matches!(Log::open(&log_path(&dir, "no_cache"), Some(&opts)), Err(Error::InvalidOptions(_))));
+    }
+
+    #[test]
+    fn buffer_options() {
+        let dir = test_dir();
+        let path = log_path(&dir, "buffer_options");
+        let mut opts = make_options(4096, Durability::Low, LogFormat::Binary);
+        opts.max_readers = 0;
+        match Log::open(&path, Some(&opts)) {
+            Err(Error::InvalidOptions(_)) => {},
+            other => panic!("Open: expected InvalidOptions, got {:?}", other.map(|_| ())),
+        }
+        opts.max_readers = 1;
+        opts.max_buffer_size = 0;
+        assert!(matches!(Log::open(&path, Some(&opts)), Err(Error::InvalidOptions(_))));
+
+        // at Low durability entries stay buffered until the buffer fills
+        let active_len = |log: &Log| fs::metadata(&log.segments.last().expect("should have segments").path).expect("should stat segment").len();
+        opts.max_buffer_size = DEFAULT_MAX_BUFFER_SIZE;
+        let mut log = Log::open(&path, Some(&opts)).expect("should open log");
+        let mut batch = Batch::default();
+        for i in 1..11 {
+            log.write(i, data_str(i)).expect("should write entry");
+            batch.write(data_str(i + 10));
+        }
+        log.write_batch(&mut batch).expect("should write batch");
+        assert_eq!(active_len(&log), HEADER_SIZE as u64);
+        log.close().expect("should close log");
+
+        opts.max_buffer_size = 64;
+        let mut log = Log::open(&path, Some(&opts)).expect("should re-open log");
+        let len = active_len(&log);
+        log.write(21, data_str(21).repeat(10)).expect("should write entry");
+        assert!(active_len(&log) > len);
+        let len = active_len(&log);
+        for i in 22..32 {
+            batch.write(data_str(i));
+        }
+        log.write_batch(&mut batch).expect("should write batch");
+        assert!(active_len(&log) > len);
+
+        // a single reader is kept for sequential reads
+        for i in 1..11 {
+            assert_eq!(log.read(i).expect("should read entry"), data_str(i).into_bytes());
+            assert_eq!(log.read(i + 20).expect("should read entry"), if i == 1 { data_str(21).repeat(10) } else { data_str(i + 20) }.into_bytes());
+            assert_eq!(log.readers.len(), 1);
+        }
+        log.close().expect("should close log");
+    }
+
+    #[test]
+    fn options_builder() {
+        use crate::codec::Deflate;
+
+        let opts = Options::builder().build().expect("should build options");
+        assert_eq!(opts.durability, DEFAULT_OPTIONS.durability);
+        assert_eq!(opts.segment_size, DEFAULT_OPTIONS.segment_size);
+        assert_eq!(opts.log_format, DEFAULT_OPTIONS.log_format);
+        assert_eq!(opts.max_readers, *MAX_READERS);
+        assert_eq!(opts.max_buffer_size, *MAX_BUFFER_SIZE);
+
+        let invalid = vec![
+            Options::builder().segment_size(0),
+            Options::builder().segment_size(1),
+            Options::builder().segment_size(HEADER_SIZE),
+            Options::builder().max_readers(0),
+            Options::builder().retention(Retention { max_segments: Some(0), ..Default::default() }),
+            Options::builder().max_segment_age(Duration::ZERO),
+            Options::builder().mmap(true).segment_compression(SegmentCompressionOptions { codec: Arc::new(Deflate::default()), block_size: 4096 }),
+            Options::builder().background_sync(SyncOptions { interval: Duration::from_secs(1), max_bytes: None }),
+            Options::builder().durability(Durability::Low).background_sync(SyncOptions { interval: Duration::ZERO, max_bytes: None }),
+        ];
+        for builder in invalid {
+            match builder.clone().build() {
+                Err(Error::InvalidOptions(_)) => {},
+                other => panic!("Build: expected InvalidOptions for {:?}, got {:?}", builder, other),
+            }
+        }
+
+        let dir = test_dir();
+        let path = log_path(&dir, "options_builder");
+        let opts = Options::builder()
+            .durability(Durability::Low)
+            .segment_size(1024)
+            .log_format(LogFormat::Binary)
+            .background_sync(SyncOptions { interval: Duration::from_millis(10), max_bytes: None })
+            .build()
+            .expect("should build options");
+        let mut log = Log::open(&path, Some(&opts)).expect("should open log");
+        write_entries(&mut log, 1..101);
+        assert!(log.segments.len() > 1);
+        test_readable(&mut log, 1, 100);
+        log.close().expect("should close log");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn permissions() {
+        use crate::codec::Deflate;
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = test_dir();
+        let path = log_path(&dir, "nested/log");
+        let mut opts = make_options(256, Durability::Medium, LogFormat::Binary);
+        opts.dir_perms = Some(0o700);
+        opts.file_perms = Some(0o600);
+        opts.retention.max_segments = Some(3);
+        opts.archive = Some(ArchiveOptions { dir: dir.path().join("archive"), compress: true });
+        opts.segment_compression = Some(SegmentCompressionOptions { codec: Arc::new(Deflate::default()), block_size: 128 });
+
+        let mut log = Log::open(&path, Some(&opts)).expect("should open log");
+        write_entries(&mut log, 1..101);
+        log.truncate_back(95).expect("should truncate back");
+        let first = log.firstindex().expect("should return first index");
+        log.truncate_front(first + 1).expect("should truncate front");
+        log.close().expect("should close log");
+
+        let mut files = 0;
+        let mut dirs = vec![dir.path().to_path_buf()];
+        while let Some(dir) = dirs.pop() {
+            for entry in fs::read_dir(&dir).expect("should read dir") {
+                let entry = entry.expect("should read entry");
+                let mode = entry.metadata().expect("should stat").permissions().mode() & 0o777;
+                if entry.file_type().expect("should have file type").is_dir() {
+                    assert_eq!(mode, 0o700, "{:?}", entry.path());
+                    dirs.push(entry.path());
+                } else {
+                    assert_eq!(mode, 0o600, "{:?}", entry.path());
+                    files += 1;
+                }
+            }
+        }
+        assert!(files > 4);
+
+        opts.file_perms = Some(0o400);
+        assert!(matches!(Log::open(&path, Some(&opts)), Err(Error::InvalidOptions(_))));
+        opts.file_perms = Some(0o600);
+        opts.dir_perms = Some(0o10700);
+        assert!(matches!(Log::open(&path, Some(&opts)), Err(Error::InvalidOptions(_))));
+    }
+
+    #[test]
+    fn lock_file() {
+        let dir = test_dir();
+        let path = log_path(&dir, "lock_file");
+        let opts = make_options(1024, Durability::Medium, LogFormat::Binary);
+
+        let mut log = Log::open(&path, Some(&opts)).expect("should open log");
+        log.write(1, data_str(1)).expect("should write entry");
+        match Log::open(&path, Some(&opts)) {
+            Err(Error::InUse) => {},
+            other => panic!("Open: expected InUse, got {:?}", other.map(|_| ())),
+        }
+        assert!(Path::new(&path).join(lock::LOCK_FILE).exists());
+
+        // close releases the lock
+        let mut log = reopen(log, &opts);
+        log.write(2, data_str(2)).expect("should write entry");
+
+        // and so does drop
+        drop(log);
+        let mut log = Log::open(&path, Some(&opts)).expect("should re-open log");
+        test_readable(&mut log, 1, 2);
+        log.close().expect("should close log");
+    }
+
+    #[test]
+    fn read_only() {
+        let dir = test_dir();
+        let path = log_path(&dir, "read_only");
+        let opts = make_options(1024, Durability::Medium, LogFormat::Binary);
+        match Log::open_read_only(&path, Some(&opts)) {
+            Err(Error::NotFound) => {},
+            other => panic!("OpenReadOnly: expected NotFound, got {:?}", other.map(|_| ())),
+        }
+        assert!(!Path::new(&path).exists());
+
+        let mut log = Log::open(&path, Some(&opts)).expect("should open log");
+        write_entries(&mut log, 1..101);
+        assert!(matches!(Log::open_read_only(&path, Some(&opts)), Err(Error::InUse)));
+        log.close().expect("should close log");
+
+        // an interrupted truncate_front leaves a START segment behind
+        let segments = load_segments(&path).expect("should load segments").2;
+        let start = segments[1].index;
+        fs::rename(&segments[1].path, format!("{}.START", segments[1].path.display())).expect("should rename segment");
+        let listing = || {
+            let mut files: Vec<(PathBuf, u64)> = fs::read_dir(&path).expect("should read dir")
+                .map(|e| e.expect("should read entry"))
+                .map(|e| (e.path(), e.metadata().expect("should stat").len()))
+                .collect();
+            files.sort();
+            files
+        };
+        let before = listing();
+
+        let mut log = Log::open_read_only(&path, Some(&opts)).expect("should open log read-only");
+        let mut other = Log::open_read_only(&path, Some(&opts)).expect("should share the lock");
+        assert!(log.is_read_only());
+        assert!(matches!(Log::open(&path, Some(&opts)), Err(Error::InUse)));
+        test_readable(&mut log, start, 100);
+        test_first_last(&other, start, 100);
+        let mut iter = other.iter_from(start);
+        while let Some((index, data)) = iter.read_next().expect("should read entry") {
+            assert_eq!(data, data_str(index).as_bytes());
+        }
+        other.verify().expect("should verify");
+
+        let mut batch = Batch::default();
+        batch.write(data_str(101));
+        assert!(matches!(log.write(101, data_str(101)), Err(Error::ReadOnly)));
+        assert!(matches!(log.write_batch(&mut batch), Err(Error::ReadOnly)));
+        assert!(matches!(log.truncate_front(start + 1), Err(Error::ReadOnly)));
+        assert!(matches!(log.truncate_back(99), Err(Error::ReadOnly)));
+        assert!(matches!(log.maybe_rotate(), Err(Error::ReadOnly)));
+        log.close().expect("should close log");
+        other.close().expect("should close log");
+        assert_eq!(listing(), before);
+
+        // a writable open finishes the truncation
+        let mut log = Log::open(&path, Some(&opts)).expect("should open log");
+        test_first_last(&log, start, 100);
+        log.write(101, data_str(101)).expect("should write entry");
+        log.close().expect("should close log");
+        assert!(!segments[0].path.exists());
+
+        // a log without a LOCK file gets one, which keeps writers out
+        let lock_path = Path::new(&path).join(lock::LOCK_FILE);
+        fs::remove_file(&lock_path).expect("should remove lock file");
+        let log = Log::open_read_only(&path, Some(&opts)).expect("should open log read-only");
+        assert!(lock_path.exists());
+        assert!(matches!(Log::open(&path, Some(&opts)), Err(Error::InUse)));
+        drop(log);
+    }
+
+    #[test]
+    fn shutdown() {
+        let dir = test_dir();
+        let path = log_path(&dir, "shutdown");
+        let mut opts = make_options(1024, Durability::Low, LogFormat::Binary);
+
+        // dropping the log flushes its buffer and releases the lock
+        let mut log = Log::open(&path, Some(&opts)).expect("should open log");
+        write_entries(&mut log, 1..11);
+        assert_eq!(log.durable_index(), 0);
+        drop(log);
+
+        opts.background_sync = Some(SyncOptions { interval: Duration::from_secs(3600), max_bytes: None });
+        let mut log = Log::open(&path, Some(&opts)).expect("should re-open log");
+        test_readable(&mut log, 1, 10);
+        write_entries(&mut log, 11..21);
+        drop(log);
+
+        // shutdown syncs at every level and reports what is on disk
+        let mut log = Log::open(&path, Some(&opts)).expect("should re-open log");
+        test_readable(&mut log, 1, 20);
+        write_entries(&mut log, 21..31);
+        assert_eq!(log.durable_index(), 20);
+        assert_eq!(log.shutdown().expect("should shut down"), 30);
+
+        let log = Log::open(&path, Some(&opts)).expect("should re-open log");
+        test_first_last(&log, 1, 30);
+        assert!(matches!(Log::open_read_only(&path, Some(&opts)), Err(Error::InUse)));
+        drop(log);
+    }
+
+    #[test]
+    fn parallel_open() {
+        let dir = test_dir();
+        let path = log_path(&dir, "parallel_open");
+        let mut opts = make_options(128, Durability::Medium, LogFormat::Binary);
+        opts.footer_offsets = true;
+
+        let mut log = Log::open(&path, Some(&opts)).expect("should open log");
+        write_entries(&mut log, 1..1001);
+        let segments = log.segments.clone();
+        assert!(segments.len() > 100);
+        log.close().expect("should close log");
+
+        // segment files created out of index order are still read in order
+        let shuffled = log_path(&dir, "shuffled");
+        fs::create_dir_all(&shuffled).expect("should create dir");
+        for i in (1..segments.len()).step_by(2).rev().chain((0..segments.len()).step_by(2)) {
+            let name = segments[i].path.file_name().expect("segment should have a file name");
+            fs::copy(&segments[i].path, Path::new(&shuffled).join(name)).expect("should copy segment");
+        }
+        let mut log = Log::open(&shuffled, Some(&opts)).expect("should open shuffled log");
+        test_readable(&mut log, 1, 1000);
+        log.close().expect("should close log");
+
+        opts.validate_threads = 4;
+        opts.open_checksums = true;
+        let mut log = Log::open(&path, Some(&opts)).expect("should re-open log");
+        test_readable(&mut log, 1, 1000);
+        log.close().expect("should close log");
+
+        // more threads than sealed segments
+        opts.validate_threads = 1000;
+        let mut log = Log::open(&path, Some(&opts)).expect("should re-open log");
+        test_first_last(&log, 1, 1000);
+        log.close().expect("should close log");
+
+        // a flipped byte is only caught with open_checksums
+        let sealed = &segments[segments.len() / 2].path;
+        let mut contents = fs::read(sealed).expect("should read segment");
+        contents[HEADER_SIZE + 4] ^= 0xff;
+        fs::write(sealed, contents).expect("should write segment");
+
+        assert!(matches!(Log::open(&path, Some(&opts)), Err(Error::Corrupt)));
+        opts.open_checksums = false;
+        let mut log = Log::open(&path, Some(&opts)).expect("should open without checksums");
+        log.close().expect("should close log");
+
+        assert!(matches!(Options::builder().validate_threads(0).build(), Err(Error::InvalidOptions(_))));
+    }
+
+    #[test]
+    fn fragments() {
+        use crate::codec::Deflate;
+        use crate::crypto::StaticKeys;
+        use std::io::Read;
+        use std::sync::Arc;
+
+        // every tenth entry is a blob several segments large
+        let blob = |i: u64| -> Vec<u8> { (0..i as usize * 100).map(|j| (j % 251) as u8 ^ i as u8).collect() };
+        let data = |i: u64| if i.is_multiple_of(10) { blob(i) } else { data_str(i).into_bytes() };
+        let dir = test_dir();
+
+        for (name, log_format, packed) in &[("binary", LogFormat::Binary, false), ("json", LogFormat::JSON, false), ("packed", LogFormat::Binary, true)] {
+            let path = log_path(&dir, name);
+            let mut opts = make_options(1024, Durability::Low, log_format.clone());
+            opts.fragment_size = Some(256);
+            opts.footer_offsets = true;
+            opts.timestamps = true;
+            opts.mmap = *log_format == LogFormat::Binary;
+            opts.retention.max_segments = Some(3);
+            opts.archive = Some(ArchiveOptions { dir: PathBuf::from(format!("{}.archive", path)), compress: false });
+            if *packed {
+                opts.compression = Some(CompressionOptions { codec: Arc::new(Deflate::default()), min_size: 0 });
+                opts.encryption = Some(Arc::new(StaticKeys::new(1, [1; 32])));
+            }
+
+            let mut log = Log::open(&path, Some(&opts)).expect("should open log");
+            let headers = [Header { key: "kind".to_string(), value: b"blob".to_vec() }];
+            for i in 1..51 {
+                log.write_with_headers(i, data(i), if i.is_multiple_of(10) { &headers } else { &[] }).expect("should write entry");
+            }
+            let mut batch = Batch::default();
+            for i in 51..100 {
+                batch.write(data(i));
+            }
+            log.write_batch(&mut batch).expect("should write batch");
+            log.write_with_headers(100, data(100), &headers).expect("should write entry");
+
+            // Read -- fragments are joined, sequentially and at random
+            let first = log.firstindex().expect("should return first index");
+            assert!(first > 1);
+            for i in first..101 {
+                assert_eq!(log.read(i).expect("should read entry"), data(i));
+            }
+            for i in (first..101).rev() {
+                assert_eq!(log.read_bytes(i).expect("should read bytes").as_ref(), data(i).as_slice());
+            }
+            let entry = log.read_entry(100).expect("should read entry");
+            assert_eq!(entry.headers, headers);
+            assert!(entry.timestamp.is_some());
+            let mut iter = log.iter_from(first);
+            while let Some((i, read)) = iter.read_next().expect("should read next") {
+                assert_eq!(read, data(i).as_slice());
+            }
+
+            // Stream -- in reads smaller than a fragment
+            for i in [first, 100] {
+                let mut stream = log.read_stream(i).expect("should open stream");
+                let mut read = Vec::new();
+                let mut chunk = [0; 100];
+                loop {
+                    match stream.read(&mut chunk).expect("should read stream") {
+                        0 => break,
+                        n => read.extend_from_slice(&chunk[..n]),
+                    }
+                }
+                assert_eq!(read, data(i));
+            }
+            assert!(matches!(log.read_stream(101), Err(Error::NotFound)));
+
+            // Archive -- retention moved whole entries out
+            let archive = log.archive().expect("should have an archive");
+            for i in 1..first {
+                assert_eq!(archive.read(i).expect("should read archived entry"), data(i));
+            }
+
+            // a blob continues in the following segments, none of which
+            // holds anything near a whole one
+            assert!(log.segments.iter().filter(|s| s.index == 100 && s.fragment > 0).count() >= 2);
+            for segment in &log.segments {
+                assert!(fs::metadata(&segment.path).expect("should stat segment").len() < 4096);
+            }
+            // the next entry follows the end of the blob in its last segment
+            log.write(101, data_str(101)).expect("should write entry");
+            assert_eq!(log.find_segment(101) as usize, log.segments.len() - 1);
+            assert!(log.segments.last().expect("should have segments").fragment > 0);
+            log.verify().expect("should verify");
+            log.close().expect("should close log");
+
+            // the blobs below would push the entries read back out
+            opts.retention.max_segments = None;
+            let mut log = Log::open(&path, Some(&opts)).expect("should re-open log");
+            let first = log.firstindex().expect("should return first index");
+            for i in first..102 {
+                assert_eq!(log.read(i).expect("should read entry"), data(i));
+            }
+
+            // Crash -- an entry missing its last fragment is dropped on open,
+            // along with the segments it continued in
+            log.write(102, blob(102)).expect("should write entry");
+            log.flush().expect("should flush");
+            let last_path = log.segments.last().expect("should have segments").path.clone();
+            assert_eq!(log.segments.last().expect("should have segments").index, 102);
+            crash(log);
+            let contents = fs::read(&last_path).expect("should read segment");
+            fs::write(&last_path, &contents[..contents.len() - 10]).expect("should write segment");
+
+            let mut log = Log::open(&path, Some(&opts)).expect("should re-open log");
+            test_first_last(&log, first, 101);
+            assert!(!log.segments.iter().any(|s| s.index == 102 && s.fragment > 0));
+            log.write(102, data_str(102)).expect("should write entry");
+
+            let mut log = reopen(log, &opts);
+            for i in first..103 {
+                let expect = if i == 102 { data_str(102).into_bytes() } else { data(i) };
+                assert_eq!(log.read(i).expect("should read entry"), expect);
+            }
+
+            // Truncate -- back to the blob, which keeps all its segments
+            log.truncate_back(100).expect("should truncate back");
+            test_first_last(&log, first, 100);
+            assert_eq!(log.read(100).expect("should read entry"), data(100));
+            log.verify().expect("should verify");
+            log.write(101, data_str(101)).expect("should write entry");
+
+            // and the front past it, which archives the blob across files
+            log.truncate_front(101).expect("should truncate front");
+            test_first_last(&log, 101, 101);
+            let archive = log.archive().expect("should have an archive");
+            assert_eq!(archive.read(100).expect("should read archived entry"), data(100));
+            assert_eq!(archive.read(99).expect("should read archived entry"), data(99));
+            log.verify().expect("should verify");
+
+            let mut log = reopen(log, &opts);
+            test_first_last(&log, 101, 101);
+            log.close().expect("should close log");
+        }
+
+        assert!(matches!(Options::builder().fragment_size(0).build(), Err(Error::InvalidOptions(_))));
     }
 
     fn data_str(i: u64) -> String {
@@ -891,9 +4172,9 @@ mod test {
     }
 
     
-    fn test_log(path: &str, mut N: u64, opts: Option<&Options>) {
+    fn test_log(path: &str, mut n: u64, opts: Option<&Options>) {
         // println!("in it");
-        // let mut N: u64 = 100;
+        // let mut n: u64 = 100;
         // unimplemented!();
         // let path = "testlog/log";
         let mut log = Log::open(path, opts).expect("should open log");
@@ -907,7 +4188,7 @@ mod test {
         assert_eq!(last_index, 0, "LastIndex: expected {}, got {}", 0, last_index);
 
 
-        for i in 1..N+1 {
+        for i in 1..n+1 {
             // write - try to apprend previous index should fial
             match log.write(i-1, data_str(i)) {
                 Err(Error::OutofOrder) => {},
@@ -936,7 +4217,7 @@ mod test {
         };
 
         // Read - read back all entries
-        for i in 1..N {
+        for i in 1..n {
             let data = log.read(i).expect("Read: should read entry");
             assert_eq!(
                 str::from_utf8(&data).expect("should be valid"),
@@ -948,7 +4229,7 @@ mod test {
         }
 
         // Read - read back first half entries
-        for i in 1..N/2 {
+        for i in 1..n/2 {
             let data = log.read(i).expect("Read: should read entry");
             assert_eq!(
                 str::from_utf8(&data).expect("should be valid"),
@@ -967,7 +4248,7 @@ mod test {
         assert_eq!(first_index, 1, "FirstIndex1: expected {}, got {}", 1, first_index);
 
         let last_index = log.lastindex().expect("Should return last index");
-        assert_eq!(last_index, N, "LastIndex: expected {}, got {}", N, last_index);
+        assert_eq!(last_index, n, "LastIndex: expected {}, got {}", n, last_index);
 
         // Close - close the log
         log.close().expect("Close: should close log");
@@ -1013,7 +4294,7 @@ mod test {
         let mut log = Log::open(path, None).expect("should re-open log");
         
         // Read - read back all entries
-        for i in 1..N+1 {
+        for i in 1..n+1 {
             let data = log.read(i).expect("Read: should read entry");
             assert_eq!(
                 str::from_utf8(&data).expect("should be valid"),
@@ -1030,10 +4311,10 @@ mod test {
 
         // LastIndex
         let last_index = log.lastindex().expect("Should return last index");
-        assert_eq!(last_index, N, "LastIndex: expected {}, got {}", N, last_index);
+        assert_eq!(last_index, n, "LastIndex: expected {}, got {}", n, last_index);
 
         // Write -- add 50 more items
-        for i in N+1..N+51 {
+        for i in n+1..n+51 {
             // Write - append next item
             log.write(i, data_str(i)).expect("Write: should append item successfully");
 
@@ -1048,7 +4329,7 @@ mod test {
                 str::from_utf8(&data).expect("should be valid"),
             );
         }
-        N += 50;
+        n += 50;
         // FirstIndex/LastIndex -- check valid first and last indexes
 
         // FirstIndex - should be 1
@@ -1056,7 +4337,7 @@ mod test {
         assert_eq!(first_index, 1, "FirstIndex: expected {}, got {}", 1, first_index);
 
         let last_index = log.lastindex().expect("Should return last index");
-        assert_eq!(last_index, N, "LastIndex: expected {}, got {}", N, last_index);
+        assert_eq!(last_index, n, "LastIndex: expected {}, got {}", n, last_index);
 
 
         // Batch -- test batch writes
@@ -1066,15 +4347,15 @@ mod test {
 
         // Write 100 entries in batches of 10
         for _i in 1..11 {
-            for j in 1..11 {
-                N += 1;
-                batch.write(&data_str(N));
+            for _j in 1..11 {
+                n += 1;
+                batch.write(data_str(n));
             }
             log.write_batch(&mut batch).expect("Failed to write batch");
         }
         
         // Read -- read back all entries
-        for i in 1..N+1 {
+        for i in 1..n+1 {
             let data = log.read(i).expect("Read: should read entry");
             assert_eq!(
                 str::from_utf8(&data).expect("should be valid"),
@@ -1096,22 +4377,22 @@ mod test {
             Err(Error::OutOfRange) => {},
             _ => panic!("TruncateFront: Expected OutOfRange error")
         };
-        test_first_last(&log,1, N);
+        test_first_last(&log,1, n);
 
         // TruncateFront -- Remove no entries
         match log.truncate_front(1) {
             // Err(Error::OutOfRange) => {},
             Ok(()) => {}
-            Err(e) => panic!(format!("TruncateFront Error: {}", e))
+            Err(e) => panic!("TruncateFront Error: {}", e)
         };
-        test_first_last(&log, 1, N);
+        test_first_last(&log, 1, n);
         // TruncateFront -- Remove first 80 entries
         match log.truncate_front(81) {
             // Err(Error::OutOfRange) => {},
             Ok(()) => {},
             _ => panic!("TruncateFront: Expected OutOfRange error")
         };
-        test_first_last(&log, 81, N);
+        test_first_last(&log, 81, n);
 
         //@TODO Write -- one entry, so the buffer might be activated
         //@TODO Read -- one random read, so there is an opened reader
@@ -1120,30 +4401,30 @@ mod test {
         //@TODO TruncateBack -- should fail, out of range
 
         // TruncateBack -- Remove no entries
-        // println!("N = {}", N);
-        match log.truncate_back(N) {
+        // println!("n = {}", n);
+        match log.truncate_back(n) {
             Ok(()) => {},
-            Err(e) => panic!(format!("TruncateBackN: {}", e))
+            Err(e) => panic!("TruncateBackN: {}", e)
         };
-        test_first_last(&log, 81, N);
+        test_first_last(&log, 81, n);
 
         // TruncateBack -- Remove last 80 entries
-        match log.truncate_back(N - 80) {
+        match log.truncate_back(n - 80) {
             Ok(()) => {},
-            Err(e) => panic!(format!("TruncateBack80: {}", e))
+            Err(e) => panic!("TruncateBack80: {}", e)
         };
-        N -= 80;
-        test_first_last(&log, 81, N);
+        n -= 80;
+        test_first_last(&log, 81, n);
 
         // Close -- close log after truncating
         log.close().expect("Should close file");
 
         // Open -- open log after truncating
         let mut log = Log::open(path, None).expect("should re-open log after truncating");
-        test_first_last(&log, 81, N);
+        test_first_last(&log, 81, n);
 
         // Read -- read back all entries
-        for i in 81..N+1 {
+        for i in 81..n+1 {
             let data = log.read(i).expect("Read: should read entry");
             assert_eq!(
                 str::from_utf8(&data).expect("should be valid"),
@@ -1155,31 +4436,31 @@ mod test {
         }
 
         // TruncateFront -- truncate all entries but one
-        log.truncate_front(N).expect("TruncateFront: all entries but one");
-        test_first_last(&log, N, N);
+        log.truncate_front(n).expect("TruncateFront: all entries but one");
+        test_first_last(&log, n, n);
 
         // Write --- write on entry
         // println!("ppppppp");
-        log.write(N+1, data_str(N+1)).expect("Write: should write on entry");
-        N += 1;
-        test_first_last(&log, N-1, N);
+        log.write(n+1, data_str(n+1)).expect("Write: should write on entry");
+        n += 1;
+        test_first_last(&log, n-1, n);
 
        // TruncateBack -- truncate all entries but one
-        match log.truncate_back(N - 1) {
+        match log.truncate_back(n - 1) {
             Ok(()) => {},
-            Err(e) => panic!(format!("TruncateBack1: {}", e))
+            Err(e) => panic!("TruncateBack1: {}", e)
         };
-        N -= 1;
-        test_first_last(&log, N, N);
+        n -= 1;
+        test_first_last(&log, n, n);
 
         // log.sync();
         // Write again
-        log.write(N+1, data_str(N+1)).expect("Write: should write on entry");
-        N += 1;
+        log.write(n+1, data_str(n+1)).expect("Write: should write on entry");
+        n += 1;
         // sync
-        log.sync();
+        log.sync().expect("should sync");
 
-        test_first_last(&log, N - 1 , N);
+        test_first_last(&log, n - 1 , n);
 
     }
 
@@ -1187,7 +4468,8 @@ mod test {
         Options {
             segment_size: segment_size as usize,
             durability,
-            log_format
+            log_format,
+            ..Options::default()
         }
     }
 }
\ No newline at end of file
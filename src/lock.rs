@@ -0,0 +1,81 @@
+use std::fs::File;
+use std::path::Path;
+use crate::error::Error;
+use crate::file_options;
+
+// Name of the file locked in the log directory
+pub(crate) const LOCK_FILE: &str = "LOCK";
+
+// LockFile holds an advisory flock on the LOCK file of a log directory,
+// released when it is dropped or the process exits. A writer holds it
+// exclusively, so a second process opening the log gets Error::InUse
+// instead of interleaving appends into the same segment. Read-only logs
+// share it among each other.
+#[derive(Debug)]
+pub(crate) struct LockFile {
+    file: File,
+}
+
+impl LockFile {
+    pub(crate) fn exclusive(dir: &Path, mode: Option<u32>) -> Result<LockFile, Error> {
+        let file = file_options(mode).read(true).write(true).create(true).truncate(false).open(dir.join(LOCK_FILE))?;
+        lock(&file, true)?;
+        Ok(LockFile { file })
+    }
+
+    // Shared lock for a read-only log. The LOCK file is created when the log
+    // has none, as it was not opened for writing since locking was added,
+    // so a writer opening the log later is still kept out. Fails when it
+    // can't be created, on a read-only filesystem for one.
+    pub(crate) fn shared(dir: &Path, mode: Option<u32>) -> Result<LockFile, Error> {
+        let path = dir.join(LOCK_FILE);
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => file_options(mode).write(true).create(true).truncate(false).open(&path)?,
+            Err(e) => return Err(Error::File(e)),
+        };
+        lock(&file, false)?;
+        Ok(LockFile { file })
+    }
+}
+
+impl Drop for LockFile {
+    fn drop(&mut self) {
+        unlock(&self.file);
+    }
+}
+
+#[cfg(unix)]
+fn lock(file: &File, exclusive: bool) -> Result<(), Error> {
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+
+    let operation = if exclusive { libc::LOCK_EX } else { libc::LOCK_SH };
+    loop {
+        if unsafe { libc::flock(file.as_raw_fd(), operation | libc::LOCK_NB) } == 0 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        match err.raw_os_error() {
+            Some(libc::EINTR) => continue,
+            Some(libc::EWOULDBLOCK) => return Err(Error::InUse),
+            _ => return Err(Error::File(err)),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn unlock(file: &File) {
+    use std::os::unix::io::AsRawFd;
+
+    // closing the file releases the lock as well, this only makes it explicit
+    unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_UN) };
+}
+
+#[cfg(not(unix))]
+fn lock(_file: &File, _exclusive: bool) -> Result<(), Error> {
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn unlock(_file: &File) {}
@@ -10,7 +10,7 @@ fn data_str(i: u64) -> String {
 
 
 fn test_log() {
-    let mut N: u64 = 10;
+    let mut n: u64 = 10;
     // unimplemented!();
     let path = "testlog/log";
     let mut log = Log::open(path, None).expect("should open log");
@@ -24,7 +24,7 @@ fn test_log() {
     assert_eq!(last_index, 0, "LastIndex: expected {}, got {}", 0, last_index);
 
 
-    for i in 1..N+1 {
+    for i in 1..n+1 {
         // write - try to apprend previous index should fial
         match log.write(i-1, data_str(i)) {
             Err(Error::OutofOrder) => {},
@@ -53,7 +53,7 @@ fn test_log() {
     };
 
     // Read - read back all entries
-    for i in 1..N {
+    for i in 1..n {
         let data = log.read(i).expect("Read: should read entry");
         assert_eq!(
             str::from_utf8(&data).expect("should be valid"),
@@ -65,7 +65,7 @@ fn test_log() {
     }
 
     // Read - read back first half entries
-    for i in 1..N/2 {
+    for i in 1..n/2 {
         let data = log.read(i).expect("Read: should read entry");
         assert_eq!(
             str::from_utf8(&data).expect("should be valid"),
@@ -84,7 +84,7 @@ fn test_log() {
     assert_eq!(first_index, 1, "FirstIndex1: expected {}, got {}", 1, first_index);
 
     let last_index = log.lastindex().expect("Should return last index");
-    assert_eq!(last_index, N, "LastIndex: expected {}, got {}", N, last_index);
+    assert_eq!(last_index, n, "LastIndex: expected {}, got {}", n, last_index);
 
     // Close - close the log
     log.close().expect("Close: should close log");
@@ -130,7 +130,7 @@ fn test_log() {
     let mut log = Log::open(path, None).expect("should re-open log");
     
     // Read - read back all entries
-    for i in 1..N+1 {
+    for i in 1..n+1 {
         let data = log.read(i).expect("Read: should read entry");
         assert_eq!(
             str::from_utf8(&data).expect("should be valid"),
@@ -147,10 +147,10 @@ fn test_log() {
 
     // LastIndex
     let last_index = log.lastindex().expect("Should return last index");
-    assert_eq!(last_index, N, "LastIndex: expected {}, got {}", N, last_index);
+    assert_eq!(last_index, n, "LastIndex: expected {}, got {}", n, last_index);
 
     // Write -- add 50 more items
-    for i in N+1..N+51 {
+    for i in n+1..n+51 {
         // Write - append next item
         log.write(i, data_str(i)).expect("Write: should append item successfully");
 
@@ -165,7 +165,7 @@ fn test_log() {
             str::from_utf8(&data).expect("should be valid"),
         );
     }
-    N += 50;
+    n += 50;
     // FirstIndex/LastIndex -- check valid first and last indexes
 
     // FirstIndex - should be 1
@@ -173,7 +173,7 @@ fn test_log() {
     assert_eq!(first_index, 1, "FirstIndex: expected {}, got {}", 1, first_index);
 
     let last_index = log.lastindex().expect("Should return last index");
-    assert_eq!(last_index, N, "LastIndex: expected {}, got {}", N, last_index);
+    assert_eq!(last_index, n, "LastIndex: expected {}, got {}", n, last_index);
 
 
     // Batch -- test batch writes
@@ -183,15 +183,15 @@ fn test_log() {
 
     // Write 100 entries in batches of 10
     for _i in 1..11 {
-        for j in 1..11 {
-            N += 1;
-            batch.write(&data_str(N));
+        for _j in 1..11 {
+            n += 1;
+            batch.write(data_str(n));
         }
         log.write_batch(&mut batch).expect("Failed to write batch");
     }
     
     // Read -- read back all entries
-    for i in 1..N+1 {
+    for i in 1..n+1 {
         let data = log.read(i).expect("Read: should read entry");
         assert_eq!(
             str::from_utf8(&data).expect("should be valid"),
@@ -1,8 +1,29 @@
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
-use std::fs::File;
+use std::fmt;
 use std::io::BufReader;
+use std::sync::Arc;
+use std::time::Duration;
+use crate::codec::Codec;
+use crate::crypto::KeyProvider;
+use crate::error::Error;
+use crate::segment::{HEADER_SIZE, SegmentFile};
+use crate::DEFAULT_OPTIONS;
 
+// What survives a crash at each durability level:
+//
+// Low     written entries are buffered and reach the OS once the buffer
+//         fills, on sync and on close. Nothing is synced until sync or
+//         close, so a process crash loses the buffer, unless
+//         background_sync bounds the loss to its interval.
+// Medium  every write reaches the OS before it returns and survives a
+//         process crash. Sealed segments are synced, and the directory is
+//         synced after segments are created, renamed or removed, so a
+//         power loss can only lose entries of the active segment.
+// High    as Medium, and every write is synced before it returns: an
+//         fdatasync, or an fsync when the segment file grew.
+//
+// Sync and close sync the active segment at every level.
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd)]
 pub enum Durability {
     Low,
@@ -10,34 +31,424 @@ pub enum Durability {
     High
 }
 
-#[derive(Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub enum LogFormat {
     Binary,
     JSON
 }
 
+// Options is non exhaustive so that settings can be added without breaking
+// callers: start from Options::builder() or Options::default().
 #[derive(Debug, Clone)]
+#[non_exhaustive]
 pub struct Options {
     pub durability: Durability,
     pub segment_size: usize,
-    pub log_format: LogFormat
+    pub log_format: LogFormat,
+    // stamp every appended entry with the current time in
+    // milliseconds since the unix epoch, off by default
+    pub timestamps: bool,
+    pub retention: Retention,
+    // move segments dropped from the front of the log into an archive
+    // instead of deleting them
+    pub archive: Option<ArchiveOptions>,
+    // seal the active segment once its oldest entry reaches this age, even
+    // when it is smaller than segment_size
+    pub max_segment_age: Option<Duration>,
+    // compress entry data on append
+    pub compression: Option<CompressionOptions>,
+    // compress whole segments once they are sealed
+    pub segment_compression: Option<SegmentCompressionOptions>,
+    // encrypt entry data with keys from this provider. Timestamps, headers
+    // and codec ids are authenticated but stay readable, headers must not
+    // hold anything confidential.
+    pub encryption: Option<Arc<dyn KeyProvider>>,
+    // store the offset of every entry in the footer of sealed segments, so
+    // reads jump to an entry instead of scanning for it
+    pub footer_offsets: bool,
+    // reserve segment_size bytes of disk for every new segment, so appends
+    // do not change the file size
+    pub preallocate: bool,
+    // number of removed segment files kept for reuse as new segments,
+    // segments moved into the archive are not reused
+    pub segment_pool: usize,
+    // sync the active segment in the background when durability is below
+    // High, bounding what a crash can lose
+    pub background_sync: Option<SyncOptions>,
+    // serve read_bytes of sealed binary segments from memory mappings of
+    // their files. Removed segment files are not recycled then, as handles
+    // may still map them.
+    pub mmap: bool,
+    // number of recently read sealed segments whose entry offsets, and
+    // mappings with mmap, are kept so random reads seek straight to an entry,
+    // at least 1
+    pub segment_cache: usize,
+    // number of readers kept open for sequential reads
+    pub max_readers: usize,
+    // size of the write buffer, flushed once full at Low durability
+    pub max_buffer_size: usize,
+    // unix mode of the directories the log creates, the log directory and
+    // the archive, None for the defaults. The umask still applies.
+    pub dir_perms: Option<u32>,
+    // unix mode of the files the log creates, segments as well as temporary
+    // and sidecar files
+    pub file_perms: Option<u32>,
+    // threads validating the sealed segments on open, worth raising for
+    // logs with thousands of segments
+    pub validate_threads: usize,
+    // check sealed segments against the checksum in their footer on open,
+    // which reads them in full
+    pub open_checksums: bool,
+    // split entries with more data than this into fragments of this size,
+    // which are compressed and encrypted one by one and can be read back
+    // through read_stream without holding the whole entry in memory
+    pub fragment_size: Option<usize>
+}
+
+impl Options {
+    // Builder starts from DEFAULT_OPTIONS, so settings added later keep
+    // their defaults for existing callers
+    pub fn builder() -> OptionsBuilder {
+        OptionsBuilder { opts: Options::default() }
+    }
+
+    // Validate returns an error for settings no log can be opened with
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.segment_size <= HEADER_SIZE {
+            return Err(Error::InvalidOptions("segment_size must be larger than the segment header"));
+        }
+        if self.retention.max_segments == Some(0) {
+            return Err(Error::InvalidOptions("retention can't remove the active segment"));
+        }
+        if self.max_segment_age == Some(Duration::ZERO) {
+            return Err(Error::InvalidOptions("max_segment_age must be above zero"));
+        }
+        if let Some(compression) = &self.segment_compression {
+            if compression.block_size == 0 {
+                return Err(Error::InvalidOptions("segment compression block_size must be at least 1"));
+            }
+            if self.mmap {
+                return Err(Error::InvalidOptions("block compressed segments can't be mapped"));
+            }
+        }
+        if let Some(sync) = &self.background_sync {
+            if sync.interval == Duration::ZERO {
+                return Err(Error::InvalidOptions("background_sync interval must be above zero"));
+            }
+            if self.durability == Durability::High {
+                return Err(Error::InvalidOptions("background_sync has nothing to sync at High durability"));
+            }
+        }
+        if self.dir_perms.is_some_and(|mode| mode > 0o7777 || mode & 0o700 != 0o700) {
+            return Err(Error::InvalidOptions("dir_perms must be a mode giving the owner full access"));
+        }
+        if self.file_perms.is_some_and(|mode| mode > 0o7777 || mode & 0o600 != 0o600) {
+            return Err(Error::InvalidOptions("file_perms must be a mode letting the owner read and write"));
+        }
+        if self.validate_threads == 0 {
+            return Err(Error::InvalidOptions("validate_threads must be at least 1"));
+        }
+        if self.fragment_size == Some(0) {
+            return Err(Error::InvalidOptions("fragment_size must be at least 1"));
+        }
+        if self.max_readers == 0 {
+            return Err(Error::InvalidOptions("max_readers must be at least 1"));
+        }
+        if self.max_buffer_size == 0 {
+            return Err(Error::InvalidOptions("max_buffer_size must be at least 1"));
+        }
+        if self.segment_cache == 0 {
+            return Err(Error::InvalidOptions("segment_cache must be at least 1"));
+        }
+        Ok(())
+    }
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        DEFAULT_OPTIONS.clone()
+    }
+}
+
+// OptionsBuilder sets up Options one setting at a time, checking them on
+// build
+#[derive(Debug, Clone)]
+pub struct OptionsBuilder {
+    opts: Options,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+impl OptionsBuilder {
+    pub fn durability(mut self, durability: Durability) -> OptionsBuilder {
+        self.opts.durability = durability;
+        self
+    }
+
+    pub fn segment_size(mut self, segment_size: usize) -> OptionsBuilder {
+        self.opts.segment_size = segment_size;
+        self
+    }
+
+    pub fn log_format(mut self, log_format: LogFormat) -> OptionsBuilder {
+        self.opts.log_format = log_format;
+        self
+    }
+
+    pub fn timestamps(mut self, timestamps: bool) -> OptionsBuilder {
+        self.opts.timestamps = timestamps;
+        self
+    }
+
+    pub fn retention(mut self, retention: Retention) -> OptionsBuilder {
+        self.opts.retention = retention;
+        self
+    }
+
+    pub fn archive(mut self, archive: ArchiveOptions) -> OptionsBuilder {
+        self.opts.archive = Some(archive);
+        self
+    }
+
+    pub fn max_segment_age(mut self, age: Duration) -> OptionsBuilder {
+        self.opts.max_segment_age = Some(age);
+        self
+    }
+
+    pub fn compression(mut self, compression: CompressionOptions) -> OptionsBuilder {
+        self.opts.compression = Some(compression);
+        self
+    }
+
+    pub fn segment_compression(mut self, compression: SegmentCompressionOptions) -> OptionsBuilder {
+        self.opts.segment_compression = Some(compression);
+        self
+    }
+
+    pub fn encryption(mut self, keys: Arc<dyn KeyProvider>) -> OptionsBuilder {
+        self.opts.encryption = Some(keys);
+        self
+    }
+
+    pub fn footer_offsets(mut self, footer_offsets: bool) -> OptionsBuilder {
+        self.opts.footer_offsets = footer_offsets;
+        self
+    }
+
+    pub fn preallocate(mut self, preallocate: bool) -> OptionsBuilder {
+        self.opts.preallocate = preallocate;
+        self
+    }
+
+    pub fn segment_pool(mut self, segment_pool: usize) -> OptionsBuilder {
+        self.opts.segment_pool = segment_pool;
+        self
+    }
+
+    pub fn background_sync(mut self, sync: SyncOptions) -> OptionsBuilder {
+        self.opts.background_sync = Some(sync);
+        self
+    }
+
+    pub fn mmap(mut self, mmap: bool) -> OptionsBuilder {
+        self.opts.mmap = mmap;
+        self
+    }
+
+    pub fn segment_cache(mut self, segment_cache: usize) -> OptionsBuilder {
+        self.opts.segment_cache = segment_cache;
+        self
+    }
+
+    pub fn max_readers(mut self, max_readers: usize) -> OptionsBuilder {
+        self.opts.max_readers = max_readers;
+        self
+    }
+
+    pub fn max_buffer_size(mut self, max_buffer_size: usize) -> OptionsBuilder {
+        self.opts.max_buffer_size = max_buffer_size;
+        self
+    }
+
+    pub fn dir_perms(mut self, mode: u32) -> OptionsBuilder {
+        self.opts.dir_perms = Some(mode);
+        self
+    }
+
+    pub fn file_perms(mut self, mode: u32) -> OptionsBuilder {
+        self.opts.file_perms = Some(mode);
+        self
+    }
+
+    pub fn validate_threads(mut self, threads: usize) -> OptionsBuilder {
+        self.opts.validate_threads = threads;
+        self
+    }
+
+    pub fn open_checksums(mut self, open_checksums: bool) -> OptionsBuilder {
+        self.opts.open_checksums = open_checksums;
+        self
+    }
+
+    pub fn fragment_size(mut self, fragment_size: usize) -> OptionsBuilder {
+        self.opts.fragment_size = Some(fragment_size);
+        self
+    }
+
+    pub fn build(self) -> Result<Options, Error> {
+        self.opts.validate()?;
+        Ok(self.opts)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SyncOptions {
+    // time between two syncs
+    pub interval: Duration,
+    // sync right away once this many bytes were written since the last sync
+    pub max_bytes: Option<usize>
+}
+
+#[derive(Debug, Clone)]
+pub struct CompressionOptions {
+    pub codec: Arc<dyn Codec>,
+    // entries with less data than this are stored uncompressed
+    pub min_size: usize
+}
+
+#[derive(Debug, Clone)]
+pub struct SegmentCompressionOptions {
+    pub codec: Arc<dyn Codec>,
+    // size of the independently compressed blocks, a random read
+    // decompresses a single block
+    pub block_size: usize
+}
+
+#[derive(Debug, Clone)]
+pub struct ArchiveOptions {
+    // archive directory, relative paths are resolved against the working
+    // directory and not the log directory
+    pub dir: PathBuf,
+    // gzip archived segments
+    pub compress: bool
+}
+
+// Limits enforced after each segment cycle by removing sealed segments from
+// the front of the log. A limit set to None is not enforced.
+#[derive(Debug, Clone, Default)]
+pub struct Retention {
+    // total size of all segment files in bytes
+    pub max_bytes: Option<u64>,
+    // number of segments, including the active one
+    pub max_segments: Option<usize>,
+    // age of the newest entry in a sealed segment
+    pub max_age: Option<Duration>,
+    // consulted before a segment is removed
+    pub hook: Option<RetentionHook>
+}
+
+// Called with the first and last index of a sealed segment before retention
+// removes it. Returning false keeps the segment, and every segment after it,
+// e.g. while consumers have not acknowledged its entries yet.
+#[derive(Clone)]
+pub struct RetentionHook(Arc<dyn Fn(u64, u64) -> bool + Send + Sync>);
+
+impl RetentionHook {
+    pub fn new<F: Fn(u64, u64) -> bool + Send + Sync + 'static>(f: F) -> RetentionHook {
+        RetentionHook(Arc::new(f))
+    }
+
+    pub fn allow(&self, first_index: u64, last_index: u64) -> bool {
+        (self.0)(first_index, last_index)
+    }
+}
+
+impl fmt::Debug for RetentionHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RetentionHook")
+    }
+}
+
+// A user key/value pair stored alongside the entry payload
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub key: String,
+    pub value: Vec<u8>,
+}
+
+impl Header {
+    pub fn new<K: Into<String>, V: AsRef<[u8]>>(key: K, value: V) -> Header {
+        Header {
+            key: key.into(),
+            value: value.as_ref().to_vec(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct Entry {
     pub index: u64,
     pub data: Vec<u8>,
+    // write time in milliseconds since the unix epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub headers: Vec<Header>,
+}
+
+impl Entry {
+    // Returns the value of the first header matching key
+    pub fn header(&self, key: &str) -> Option<&[u8]> {
+        self.headers.iter().find(|h| h.key == key).map(|h| h.value.as_slice())
+    }
+}
+
+// Smallest and largest entry timestamp in a segment
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeRange {
+    pub min: u64,
+    pub max: u64,
+}
+
+impl TimeRange {
+    // A range holding no timestamps
+    pub fn empty() -> TimeRange {
+        TimeRange { min: u64::MAX, max: 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min > self.max
+    }
+
+    pub fn add(&mut self, timestamp: u64) {
+        self.min = self.min.min(timestamp);
+        self.max = self.max.max(timestamp);
+    }
 }
 
 #[derive(Debug, Default, Clone)]
 pub struct Segment {
     pub path: PathBuf,
-    pub index: u64
+    pub index: u64,
+    // number of the fragment of the entry at index the segment starts
+    // with, zero unless it continues an entry begun in an earlier segment
+    pub fragment: u64,
+    // entry time range, None until the segment is scanned or sealed
+    pub times: Option<TimeRange>,
+    // time the segment was sealed, None for the active segment and for
+    // segments whose footer does not record it
+    pub sealed: Option<u64>,
+}
+
+impl Segment {
+    // Index of the first entry starting in the segment, past the fragments
+    // of the entry it continues
+    pub fn first_entry(&self) -> u64 {
+        self.index + (self.fragment > 0) as u64
+    }
 }
 
 #[derive(Debug)]
 pub struct Reader {
     pub sindex:  u64, // segment index
     pub nindex: u64,  // next entry index
-    pub rd: BufReader<File>,
+    pub rd: BufReader<SegmentFile>,
 }
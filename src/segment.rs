@@ -0,0 +1,906 @@
+use memmap2::Mmap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::Arc;
+use crate::codec::{Codec, find_codec};
+use crate::error::Error;
+use crate::{Record, create_file, is_eof, now_millis, read_entry_format, read_entry_meta, skip_continued};
+use crate::primitives::{LogFormat, TimeRange};
+
+// Segments start with a fixed size header
+//
+//   magic version format flags first_index created key_id reserved
+//
+// Segments written before the header was added start right with their first
+// entry. Neither a JSON entry nor the big endian index of a binary entry can
+// start with the first magic byte, which tells the two apart.
+const HEADER_MAGIC: &[u8; 6] = b"WALSEG";
+pub const HEADER_VERSION: u8 = 1;
+pub const HEADER_SIZE: usize = 32;
+const HEADER_KEY_ID: u8 = 0x01;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SegmentHeader {
+    pub version: u8,
+    pub log_format: LogFormat,
+    pub first_index: u64,
+    // creation time in milliseconds since the unix epoch
+    pub created: u64,
+    // key new entries of the segment are encrypted with
+    pub key_id: Option<u32>,
+}
+
+impl SegmentHeader {
+    pub fn new(first_index: u64, log_format: LogFormat, key_id: Option<u32>) -> SegmentHeader {
+        SegmentHeader {
+            version: HEADER_VERSION,
+            log_format,
+            first_index,
+            created: now_millis(),
+            key_id,
+        }
+    }
+
+    pub fn encode(&self) -> [u8; HEADER_SIZE] {
+        let mut buf = [0; HEADER_SIZE];
+        buf[..6].copy_from_slice(HEADER_MAGIC);
+        buf[6] = self.version;
+        buf[7] = match self.log_format {
+            LogFormat::Binary => 0,
+            LogFormat::JSON => 1,
+        };
+        if let Some(key_id) = self.key_id {
+            buf[8] = HEADER_KEY_ID;
+            buf[25..29].copy_from_slice(&key_id.to_be_bytes());
+        }
+        buf[9..17].copy_from_slice(&self.first_index.to_be_bytes());
+        buf[17..25].copy_from_slice(&self.created.to_be_bytes());
+        buf
+    }
+
+    // Read the header at the start of a segment, leaving reader at its first
+    // entry. Returns None for a legacy segment without a header.
+    pub fn read<R: BufRead>(reader: &mut R) -> Result<Option<SegmentHeader>, Error> {
+        match reader.fill_buf()?.first() {
+            Some(b) if *b == HEADER_MAGIC[0] => {},
+            _ => return Ok(None),
+        }
+
+        let mut buf = [0; HEADER_SIZE];
+        reader.read_exact(&mut buf).map_err(|_| Error::Corrupt)?;
+        if &buf[..6] != HEADER_MAGIC {
+            return Err(Error::Corrupt);
+        }
+        if buf[6] == 0 || buf[6] > HEADER_VERSION {
+            return Err(Error::UnsupportedVersion);
+        }
+
+        let log_format = match buf[7] {
+            0 => LogFormat::Binary,
+            1 => LogFormat::JSON,
+            _ => return Err(Error::Corrupt),
+        };
+        let key_id = if buf[8] & HEADER_KEY_ID != 0 {
+            Some(u32::from_be_bytes([buf[25], buf[26], buf[27], buf[28]]))
+        } else {
+            None
+        };
+
+        Ok(Some(SegmentHeader {
+            version: buf[6],
+            log_format,
+            first_index: u64_at(&buf, 9),
+            created: u64_at(&buf, 17),
+            key_id,
+        }))
+    }
+
+    // Check the header belongs to a segment named after first_index and
+    // written in log_format
+    pub(crate) fn validate(&self, first_index: u64, log_format: &LogFormat) -> Result<(), Error> {
+        if self.first_index != first_index {
+            return Err(Error::Corrupt);
+        }
+        if &self.log_format != log_format {
+            return Err(Error::FormatMismatch);
+        }
+        Ok(())
+    }
+}
+
+// Returns the header of the segment file at path, None for a legacy
+// segment. codec is the custom codec the segment may be compressed with.
+pub fn read_segment_header(path: &Path, codec: Option<&Arc<dyn Codec>>) -> Result<Option<SegmentHeader>, Error> {
+    let mut reader = BufReader::with_capacity(HEADER_SIZE, SegmentFile::open(path, codec)?);
+    SegmentHeader::read(&mut reader)
+}
+
+// A compressed segment is laid out as
+//
+//   magic codec block_size block... block_index trailer
+//
+// where every block holds block_size bytes of the original segment file
+// compressed on its own, block_index lists the stored and raw size of each
+// block and trailer holds the block count, the offset of block_index and the
+// size of the original file. Reads only decompress the blocks they touch.
+const BLOCK_MAGIC: &[u8; 8] = b"WALSEGZ\x01";
+const BLOCK_HEADER_SIZE: u64 = 8 + 1 + 4;
+const BLOCK_TRAILER_SIZE: u64 = 8 + 8 + 8;
+
+#[derive(Debug, Clone, Copy)]
+struct Block {
+    offset: u64,
+    size: u32,
+    raw_offset: u64,
+    raw_size: u32,
+}
+
+// Reads the original bytes of a block compressed segment
+#[derive(Debug)]
+pub struct BlockFile {
+    file: File,
+    codec: Arc<dyn Codec>,
+    blocks: Vec<Block>,
+    stored_len: u64,
+    raw_len: u64,
+    pos: u64,
+    // the last decompressed block
+    cache: Option<(usize, Vec<u8>)>,
+}
+
+impl BlockFile {
+    fn open(mut file: File, codec: Option<&Arc<dyn Codec>>) -> Result<BlockFile, Error> {
+        let stored_len = file.metadata()?.len();
+        if stored_len < BLOCK_HEADER_SIZE + BLOCK_TRAILER_SIZE {
+            return Err(Error::Corrupt);
+        }
+
+        let mut header = [0; BLOCK_HEADER_SIZE as usize];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut header)?;
+        let codec = find_codec(header[8], codec)?;
+
+        let mut trailer = [0; BLOCK_TRAILER_SIZE as usize];
+        file.seek(SeekFrom::Start(stored_len - BLOCK_TRAILER_SIZE))?;
+        file.read_exact(&mut trailer)?;
+        let count = u64_at(&trailer, 0);
+        let index_offset = u64_at(&trailer, 8);
+        let raw_len = u64_at(&trailer, 16);
+
+        if index_offset.checked_add(count.saturating_mul(8)) != Some(stored_len - BLOCK_TRAILER_SIZE) {
+            return Err(Error::Corrupt);
+        }
+
+        let mut index = vec![0; (count * 8) as usize];
+        file.seek(SeekFrom::Start(index_offset))?;
+        file.read_exact(&mut index)?;
+
+        let mut blocks = Vec::with_capacity(count as usize);
+        let mut offset = BLOCK_HEADER_SIZE;
+        let mut raw_offset = 0;
+        for entry in index.chunks(8) {
+            let size = u32::from_be_bytes([entry[0], entry[1], entry[2], entry[3]]);
+            let raw_size = u32::from_be_bytes([entry[4], entry[5], entry[6], entry[7]]);
+            blocks.push(Block { offset, size, raw_offset, raw_size });
+            offset += size as u64;
+            raw_offset += raw_size as u64;
+        }
+
+        if offset != index_offset || raw_offset != raw_len {
+            return Err(Error::Corrupt);
+        }
+
+        Ok(BlockFile {
+            file,
+            codec,
+            blocks,
+            stored_len,
+            raw_len,
+            pos: 0,
+            cache: None,
+        })
+    }
+
+    fn block(&mut self, i: usize) -> io::Result<&[u8]> {
+        if self.cache.as_ref().map(|(b, _)| *b) != Some(i) {
+            let block = self.blocks[i];
+            let mut stored = vec![0; block.size as usize];
+            self.file.seek(SeekFrom::Start(block.offset))?;
+            self.file.read_exact(&mut stored)?;
+            let raw = self.codec.decompress(&stored).map_err(to_io_error)?;
+            if raw.len() != block.raw_size as usize {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "log corrupt"));
+            }
+            self.cache = Some((i, raw));
+        }
+        Ok(&self.cache.as_ref().expect("block should be cached").1)
+    }
+}
+
+impl Read for BlockFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.raw_len || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let pos = self.pos;
+        let i = self.blocks.partition_point(|b| b.raw_offset + b.raw_size as u64 <= pos);
+        let start = (pos - self.blocks[i].raw_offset) as usize;
+        let data = self.block(i)?;
+        let n = buf.len().min(data.len() - start);
+        buf[..n].copy_from_slice(&data[start..start + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for BlockFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::End(n) => self.raw_len.checked_add_signed(n),
+            SeekFrom::Current(n) => self.pos.checked_add_signed(n),
+        };
+        match pos {
+            Some(pos) => {
+                self.pos = pos;
+                Ok(pos)
+            },
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid seek to a negative position")),
+        }
+    }
+}
+
+// Sealed segments end with a footer
+//
+//   count first_index last_index checksum offset... min_time max_time sealed offset_count length crc magic
+//
+// where length is the size of the segment in front of the footer, checksum
+// its crc32, the optional offsets point at every entry, min_time and
+// max_time bound the entry timestamps, sealed is when the footer was
+// written and crc is the crc32 of the footer up to crc. Version 1 footers
+// lack the times.
+const FOOTER_MAGIC: &[u8; 8] = b"WALFOOT\x02";
+const FOOTER_MAGIC_V1: &[u8; 8] = b"WALFOOT\x01";
+const FOOTER_HEAD_SIZE: u64 = 8 + 8 + 8 + 4;
+const FOOTER_TAIL_SIZE: u64 = 8 + 8 + 8 + 8 + 8 + 4 + 8;
+const FOOTER_TAIL_SIZE_V1: u64 = 8 + 8 + 4 + 8;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SegmentFooter {
+    pub count: u64,
+    pub first_index: u64,
+    pub last_index: u64,
+    // size of the segment in front of the footer
+    pub length: u64,
+    // crc32 of the segment in front of the footer
+    pub checksum: u32,
+    // whether the offset of every entry is stored
+    pub has_offsets: bool,
+    // entry time range, None for version 1 footers
+    pub times: Option<TimeRange>,
+    // time the segment was sealed in milliseconds since the unix epoch,
+    // zero for version 1 footers
+    pub sealed: u64,
+}
+
+impl SegmentFooter {
+    // Check the footer covers exactly the entries first_index up to
+    // next_index, the first index of the following segment
+    pub(crate) fn validate(&self, first_index: u64, next_index: u64) -> Result<(), Error> {
+        if self.first_index != first_index || self.last_index + 1 != next_index || self.count != next_index - first_index {
+            return Err(Error::Corrupt);
+        }
+        Ok(())
+    }
+
+    // Encode the footer, table holding the offsets when it has them. A
+    // footer without a time range is encoded as version 1.
+    pub(crate) fn encode(&self, table: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(table.len() + (FOOTER_HEAD_SIZE + FOOTER_TAIL_SIZE) as usize);
+        buf.extend_from_slice(&self.count.to_be_bytes());
+        buf.extend_from_slice(&self.first_index.to_be_bytes());
+        buf.extend_from_slice(&self.last_index.to_be_bytes());
+        buf.extend_from_slice(&self.checksum.to_be_bytes());
+        buf.extend_from_slice(table);
+        if let Some(times) = &self.times {
+            buf.extend_from_slice(&times.min.to_be_bytes());
+            buf.extend_from_slice(&times.max.to_be_bytes());
+            buf.extend_from_slice(&self.sealed.to_be_bytes());
+        }
+        buf.extend_from_slice(&((table.len() / 8) as u64).to_be_bytes());
+        buf.extend_from_slice(&self.length.to_be_bytes());
+        let crc = crc32fast::hash(&buf);
+        buf.extend_from_slice(&crc.to_be_bytes());
+        buf.extend_from_slice(if self.times.is_some() { FOOTER_MAGIC } else { FOOTER_MAGIC_V1 });
+        buf
+    }
+}
+
+#[derive(Debug)]
+enum SegmentData {
+    Plain(File),
+    Blocks(BlockFile),
+}
+
+impl SegmentData {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            SegmentData::Plain(file) => file.read(buf),
+            SegmentData::Blocks(blocks) => blocks.read(buf),
+        }
+    }
+
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            SegmentData::Plain(file) => file.seek(pos),
+            SegmentData::Blocks(blocks) => blocks.seek(pos),
+        }
+    }
+
+    fn read_exact_at(&mut self, at: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.seek(SeekFrom::Start(at))?;
+        let mut read = 0;
+        while read < buf.len() {
+            match self.read(&mut buf[read..])? {
+                0 => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "segment too short")),
+                n => read += n,
+            }
+        }
+        Ok(())
+    }
+}
+
+// SegmentFile reads a segment whether it was block compressed or not. The
+// footer of a sealed segment is not part of what is read, nor is anything
+// past the limit set on the active segment.
+#[derive(Debug)]
+pub struct SegmentFile {
+    data: SegmentData,
+    pos: u64,
+    footer: Option<SegmentFooter>,
+    // the segment ends with the footer magic, but not with a footer whose
+    // lengths and crc add up
+    footer_rejected: bool,
+    limit: Option<u64>,
+}
+
+impl SegmentFile {
+    // Open the segment at path, codec being the custom codec it may have
+    // been compressed with
+    pub fn open(path: &Path, codec: Option<&Arc<dyn Codec>>) -> Result<SegmentFile, Error> {
+        let mut file = File::open(path)?;
+        let data = if is_block_file(&mut file)? {
+            SegmentData::Blocks(BlockFile::open(file, codec)?)
+        } else {
+            SegmentData::Plain(file)
+        };
+
+        let mut segment = SegmentFile { data, pos: 0, footer: None, footer_rejected: false, limit: None };
+        segment.footer = segment.read_footer()?;
+        segment.data.seek(SeekFrom::Start(0))?;
+        Ok(segment)
+    }
+
+    fn read_footer(&mut self) -> Result<Option<SegmentFooter>, Error> {
+        let raw_len = self.raw_len()?;
+        if raw_len < FOOTER_HEAD_SIZE + FOOTER_TAIL_SIZE_V1 {
+            return Ok(None);
+        }
+
+        let mut magic = [0; 8];
+        self.data.read_exact_at(raw_len - 8, &mut magic)?;
+        let tail_size = match &magic {
+            FOOTER_MAGIC => FOOTER_TAIL_SIZE,
+            FOOTER_MAGIC_V1 => FOOTER_TAIL_SIZE_V1,
+            _ => return Ok(None),
+        };
+        if raw_len < FOOTER_HEAD_SIZE + tail_size {
+            self.footer_rejected = true;
+            return Ok(None);
+        }
+
+        let mut tail = [0; FOOTER_TAIL_SIZE as usize];
+        let tail = &mut tail[..tail_size as usize];
+        self.data.read_exact_at(raw_len - tail_size, tail)?;
+        let times = (tail_size == FOOTER_TAIL_SIZE).then(|| TimeRange { min: u64_at(tail, 0), max: u64_at(tail, 8) });
+        let sealed = if tail_size == FOOTER_TAIL_SIZE { u64_at(tail, 16) } else { 0 };
+        let offsets = u64_at(tail, tail_size as usize - 28);
+        let length = u64_at(tail, tail_size as usize - 20);
+        let size = offsets.checked_mul(8).and_then(|n| n.checked_add(FOOTER_HEAD_SIZE + tail_size));
+        if size.and_then(|n| n.checked_add(length)) != Some(raw_len) {
+            self.footer_rejected = true;
+            return Ok(None);
+        }
+
+        // entry data at the end of the active segment can look like a
+        // footer, only one carrying the crc of its contents is
+        let mut buf = vec![0; (raw_len - length) as usize];
+        self.data.read_exact_at(length, &mut buf)?;
+        let crc_at = buf.len() - 12;
+        if crc32fast::hash(&buf[..crc_at]) != u32::from_be_bytes([buf[crc_at], buf[crc_at + 1], buf[crc_at + 2], buf[crc_at + 3]]) {
+            self.footer_rejected = true;
+            return Ok(None);
+        }
+
+        let head = &buf[..FOOTER_HEAD_SIZE as usize];
+        let footer = SegmentFooter {
+            count: u64_at(head, 0),
+            first_index: u64_at(head, 8),
+            last_index: u64_at(head, 16),
+            length,
+            checksum: u32::from_be_bytes([head[24], head[25], head[26], head[27]]),
+            has_offsets: offsets > 0,
+            times,
+            sealed,
+        };
+        if offsets > 0 && offsets != footer.count {
+            return Err(Error::Corrupt);
+        }
+        Ok(Some(footer))
+    }
+
+    // Footer of a sealed segment, None for the active segment and for
+    // segments sealed before footers were written
+    pub fn footer(&self) -> Option<&SegmentFooter> {
+        self.footer.as_ref()
+    }
+
+    // Whether the segment ends in what looks like a footer but fails its
+    // checks, which is corruption for a sealed segment
+    pub(crate) fn footer_rejected(&self) -> bool {
+        self.footer_rejected
+    }
+
+    // Read the segment in full, treating its footer as entry data
+    pub(crate) fn discard_footer(&mut self) {
+        self.footer = None;
+    }
+
+    // Stop reads at end, the logical end of a preallocated active segment
+    pub(crate) fn set_limit(&mut self, end: u64) {
+        self.limit = Some(end);
+    }
+
+    pub(crate) fn limit(&self) -> Option<u64> {
+        self.limit
+    }
+
+    // Position reads continue from
+    pub(crate) fn position(&self) -> u64 {
+        self.pos
+    }
+
+    // Offset of the entry at index when the footer stores offsets
+    pub fn entry_offset(&mut self, index: u64) -> Result<Option<u64>, Error> {
+        let (length, first_index) = match &self.footer {
+            Some(footer) if footer.has_offsets && index >= footer.first_index && index <= footer.last_index => (footer.length, footer.first_index),
+            _ => return Ok(None),
+        };
+
+        let mut buf = [0; 8];
+        self.data.read_exact_at(length + FOOTER_HEAD_SIZE + (index - first_index) * 8, &mut buf)?;
+        self.data.seek(SeekFrom::Start(self.pos))?;
+        Ok(Some(u64::from_be_bytes(buf)))
+    }
+
+    // Check the segment against the checksum in its footer
+    pub fn verify_checksum(&mut self) -> Result<(), Error> {
+        let checksum = match &self.footer {
+            Some(footer) => footer.checksum,
+            None => return Ok(()),
+        };
+
+        let pos = self.pos;
+        self.seek(SeekFrom::Start(0))?;
+        let mut hasher = crc32fast::Hasher::new();
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            match self.read(&mut buf)? {
+                0 => break,
+                n => hasher.update(&buf[..n]),
+            }
+        }
+        self.seek(SeekFrom::Start(pos))?;
+
+        if hasher.finalize() != checksum {
+            return Err(Error::Corrupt);
+        }
+        Ok(())
+    }
+
+    // Size of the segment file on disk
+    pub fn stored_len(&self) -> Result<u64, Error> {
+        match &self.data {
+            SegmentData::Plain(file) => Ok(file.metadata()?.len()),
+            SegmentData::Blocks(blocks) => Ok(blocks.stored_len),
+        }
+    }
+
+    // Size of the segment before it was compressed
+    pub fn raw_len(&self) -> Result<u64, Error> {
+        match &self.data {
+            SegmentData::Plain(file) => Ok(file.metadata()?.len()),
+            SegmentData::Blocks(blocks) => Ok(blocks.raw_len),
+        }
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        matches!(self.data, SegmentData::Blocks(_))
+    }
+}
+
+impl Read for SegmentFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let end = self.footer.as_ref().map_or(u64::MAX, |f| f.length).min(self.limit.unwrap_or(u64::MAX));
+        if self.pos >= end {
+            return Ok(0);
+        }
+
+        let max = (end - self.pos).min(buf.len() as u64) as usize;
+        let n = self.data.read(&mut buf[..max])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for SegmentFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        // the end of a sealed segment is where its footer starts
+        let pos = match (pos, &self.footer) {
+            (SeekFrom::End(n), Some(footer)) => match footer.length.checked_add_signed(n) {
+                Some(pos) => SeekFrom::Start(pos),
+                None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid seek to a negative position")),
+            },
+            (pos, _) => pos,
+        };
+        self.pos = self.data.seek(pos)?;
+        Ok(self.pos)
+    }
+}
+
+// Counts and checksums the bytes read through it
+struct HashingReader<R> {
+    inner: R,
+    pos: u64,
+    hasher: crc32fast::Hasher,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+// Append a footer to the sealed segment file at path, which starts at
+// first_index and holds entries written in log_format. A segment starting
+// with fragment number fragment of that entry, when not zero, covers the
+// entries after it. The offset of every entry goes into the footer when
+// offsets is set, and sealed is the time the segment was sealed at.
+pub(crate) fn write_footer(path: &Path, first_index: u64, fragment: u64, log_format: &LogFormat, offsets: bool, sealed: u64) -> Result<SegmentFooter, Error> {
+    let file = HashingReader { inner: File::open(path)?, pos: 0, hasher: crc32fast::Hasher::new() };
+    let mut reader = BufReader::new(file);
+    SegmentHeader::read(&mut reader)?;
+    skip_continued(&mut reader, log_format, first_index, fragment)?;
+
+    let first_index = first_index + (fragment > 0) as u64;
+    let mut table = Vec::new();
+    let mut count = 0;
+    let mut next_index = first_index;
+    let mut times = TimeRange::empty();
+    loop {
+        let offset = reader.get_ref().pos - reader.buffer().len() as u64;
+        match read_entry_format(&mut reader, log_format) {
+            Ok(entry) => {
+                if entry.index != next_index {
+                    return Err(Error::Corrupt);
+                }
+                if offsets {
+                    table.extend_from_slice(&offset.to_be_bytes());
+                }
+                if let Some(timestamp) = entry.timestamp {
+                    times.add(timestamp);
+                }
+                count += 1;
+                next_index += 1;
+            },
+            Err(ref e) if is_eof(e) => break,
+            Err(e) => return Err(e),
+        }
+    }
+
+    let file = reader.into_inner();
+    let footer = SegmentFooter {
+        count,
+        first_index,
+        last_index: next_index - 1,
+        length: file.pos,
+        checksum: file.hasher.finalize(),
+        has_offsets: offsets && count > 0,
+        times: Some(times),
+        sealed,
+    };
+
+    let mut file = OpenOptions::new().append(true).open(path)?;
+    file.write_all(&footer.encode(&table))?;
+    file.sync_all()?;
+    Ok(footer)
+}
+
+// Space saved by compressing sealed segments
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompressionStats {
+    // number of compressed segments
+    pub segments: usize,
+    // size of the compressed segments before compression
+    pub raw_bytes: u64,
+    // size of the compressed segments on disk
+    pub stored_bytes: u64,
+}
+
+impl CompressionStats {
+    // Stored size relative to the original size, 1.0 when nothing has been
+    // compressed yet
+    pub fn ratio(&self) -> f64 {
+        if self.raw_bytes == 0 {
+            return 1.0;
+        }
+        self.stored_bytes as f64 / self.raw_bytes as f64
+    }
+}
+
+// Compress the sealed segment file at path block by block. The compressed
+// file is written next to it and renamed over it once complete.
+pub(crate) fn compress_segment(path: &Path, codec: &Arc<dyn Codec>, block_size: usize, mode: Option<u32>) -> Result<(), Error> {
+    let mut name = path.file_name().expect("segment should have a file name").to_os_string();
+    name.push(".COMPRESS");
+    let temp_path = path.with_file_name(name);
+
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut writer = BufWriter::new(create_file(&temp_path, mode)?);
+
+    writer.write_all(BLOCK_MAGIC)?;
+    writer.write_all(&[codec.id()])?;
+    writer.write_all(&(block_size as u32).to_be_bytes())?;
+
+    let mut index = Vec::new();
+    let mut offset = BLOCK_HEADER_SIZE;
+    let mut raw_len = 0;
+    let mut raw = Vec::with_capacity(block_size);
+    loop {
+        raw.clear();
+        (&mut reader).take(block_size as u64).read_to_end(&mut raw)?;
+        if raw.is_empty() {
+            break;
+        }
+
+        let stored = codec.compress(&raw)?;
+        writer.write_all(&stored)?;
+        index.extend_from_slice(&(stored.len() as u32).to_be_bytes());
+        index.extend_from_slice(&(raw.len() as u32).to_be_bytes());
+        offset += stored.len() as u64;
+        raw_len += raw.len() as u64;
+    }
+
+    writer.write_all(&index)?;
+    writer.write_all(&((index.len() / 8) as u64).to_be_bytes())?;
+    writer.write_all(&offset.to_be_bytes())?;
+    writer.write_all(&raw_len.to_be_bytes())?;
+
+    let file = writer.into_inner().map_err(|e| Error::File(e.into_error()))?;
+    file.sync_all()?;
+    drop(file);
+
+    fs::rename(&temp_path, path)?;
+    Ok(())
+}
+
+// Undo compress_segment for the segment at path, leaving out its footer, so
+// entries can be appended to it again
+pub(crate) fn decompress_segment(path: &Path, codec: Option<&Arc<dyn Codec>>, mode: Option<u32>) -> Result<(), Error> {
+    let mut segment = SegmentFile::open(path, codec)?;
+    if !segment.is_compressed() {
+        return Ok(());
+    }
+
+    let mut name = path.file_name().expect("segment should have a file name").to_os_string();
+    name.push(".DECOMPRESS");
+    let temp_path = path.with_file_name(name);
+
+    let mut file = create_file(&temp_path, mode)?;
+    io::copy(&mut segment, &mut file)?;
+    file.sync_all()?;
+    drop(file);
+
+    fs::rename(&temp_path, path)?;
+    Ok(())
+}
+
+// Reserve size bytes of disk for file up front, so appends neither allocate
+// blocks nor change the file size. Falls back to growing the file where
+// fallocate is not supported.
+pub(crate) fn preallocate(file: &File, size: u64) -> Result<(), Error> {
+    if file.metadata()?.len() >= size {
+        return Ok(());
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+        // safe as the descriptor stays open for the duration of the call
+        let ret = unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, size as libc::off_t) };
+        if ret == 0 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::EOPNOTSUPP) {
+            return Err(Error::File(err));
+        }
+    }
+
+    file.set_len(size)?;
+    Ok(())
+}
+
+// Bytes is a cheaply cloned handle to the data of an entry. Data read from
+// a mapped segment is borrowed from the mapping, which stays alive for as
+// long as a handle to it does.
+#[derive(Clone)]
+pub struct Bytes {
+    buf: Buf,
+    start: usize,
+    end: usize,
+}
+
+#[derive(Clone)]
+enum Buf {
+    Mapped(Arc<Mmap>),
+    Owned(Arc<Vec<u8>>),
+}
+
+impl Bytes {
+    // True when the data is borrowed from a mapped segment
+    pub fn is_mapped(&self) -> bool {
+        matches!(self.buf, Buf::Mapped(_))
+    }
+}
+
+impl Deref for Bytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match &self.buf {
+            Buf::Mapped(map) => &map[self.start..self.end],
+            Buf::Owned(data) => &data[self.start..self.end],
+        }
+    }
+}
+
+impl AsRef<[u8]> for Bytes {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl From<Vec<u8>> for Bytes {
+    fn from(data: Vec<u8>) -> Bytes {
+        Bytes { start: 0, end: data.len(), buf: Buf::Owned(Arc::new(data)) }
+    }
+}
+
+impl fmt::Debug for Bytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Bytes")
+            .field("len", &self.len())
+            .field("mapped", &self.is_mapped())
+            .finish()
+    }
+}
+
+// MappedSegment maps a sealed binary segment into memory and knows where
+// each of its entries starts
+#[derive(Debug)]
+pub(crate) struct MappedSegment {
+    map: Arc<Mmap>,
+    first_index: u64,
+    offsets: Vec<usize>,
+    // end of the entries, where the footer starts
+    end: usize,
+}
+
+impl MappedSegment {
+    // Map the segment at path, None when it is block compressed and its
+    // entries can't be read in place. A segment starting with fragment
+    // number fragment of the entry at first_index, when not zero, maps the
+    // entries after it.
+    pub(crate) fn open(path: &Path, first_index: u64, fragment: u64, codec: Option<&Arc<dyn Codec>>) -> Result<Option<MappedSegment>, Error> {
+        let segment = SegmentFile::open(path, codec)?;
+        if segment.is_compressed() {
+            return Ok(None);
+        }
+
+        let file = File::open(path)?;
+        // sealed segments are never written again, files are replaced by a
+        // rename and not recycled while segments are mapped
+        let map = unsafe { Mmap::map(&file)? };
+        let end = segment.footer().map_or(map.len() as u64, |f| f.length) as usize;
+        if end > map.len() {
+            return Err(Error::Corrupt);
+        }
+
+        let mut data = &map[..end];
+        if let Some(header) = SegmentHeader::read(&mut data)? {
+            header.validate(first_index, &LogFormat::Binary)?;
+        }
+
+        let first_index = first_index + (fragment > 0) as u64;
+        let mut offsets = Vec::new();
+        // the fragments following the first one of an entry share its index
+        let mut fragment = fragment > 0;
+        loop {
+            let offset = end - data.len();
+            let next_index = first_index + offsets.len() as u64 - fragment as u64;
+            let (record, size) = match read_entry_meta(&mut data) {
+                Ok((record, _)) if record.index != next_index => return Err(Error::Corrupt),
+                Ok(meta) => meta,
+                Err(ref e) if is_eof(e) => break,
+                Err(e) => return Err(e),
+            };
+            // a torn entry at the end of a legacy segment
+            if size > data.len() {
+                break;
+            }
+            data = &data[size..];
+            if !fragment {
+                offsets.push(offset);
+            }
+            fragment = record.fragment;
+        }
+
+        Ok(Some(MappedSegment { map: Arc::new(map), first_index, offsets, end }))
+    }
+
+    // Record of the entry at index without its data, which is returned as
+    // a handle into the mapping
+    pub(crate) fn read(&self, index: u64) -> Result<(Record, Bytes), Error> {
+        let offset = index.checked_sub(self.first_index)
+            .and_then(|i| self.offsets.get(i as usize))
+            .ok_or(Error::NotFound)?;
+
+        let mut data = &self.map[*offset..self.end];
+        let (record, size) = read_entry_meta(&mut data)?;
+        let start = self.end - data.len();
+        Ok((record, Bytes { buf: Buf::Mapped(self.map.clone()), start, end: start + size }))
+    }
+}
+
+fn is_block_file(file: &mut File) -> Result<bool, Error> {
+    let mut magic = [0; 8];
+    let mut read = 0;
+    while read < magic.len() {
+        match file.read(&mut magic[read..])? {
+            0 => return Ok(false),
+            n => read += n,
+        }
+    }
+    Ok(&magic == BLOCK_MAGIC)
+}
+
+fn u64_at(buf: &[u8], at: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&buf[at..at + 8]);
+    u64::from_be_bytes(bytes)
+}
+
+fn to_io_error(err: Error) -> io::Error {
+    match err {
+        Error::File(e) => e,
+        e => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+    }
+}
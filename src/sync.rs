@@ -0,0 +1,118 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+// Writer appends to the active segment. It is shared with the background
+// syncer, which needs the write buffer as well as the file.
+#[derive(Debug)]
+pub(crate) struct Writer {
+    pub(crate) file: BufWriter<File>,
+    // size of the file as of its last full fsync
+    synced_len: u64,
+    // index of the last entry handed to the writer
+    pub(crate) written_index: u64,
+    // index of the last entry synced to disk
+    pub(crate) durable_index: u64,
+    // bytes written since the last sync
+    pub(crate) unsynced: usize,
+    // error hit by the background syncer, returned by the next write
+    pub(crate) error: Option<io::Error>,
+    // signalled whenever durable_index moves
+    pub(crate) synced: Arc<Condvar>,
+}
+
+impl Writer {
+    // Writer for file, whose entries up to index are on disk, buffering up
+    // to capacity bytes
+    pub(crate) fn new(file: File, index: u64, capacity: usize) -> io::Result<Writer> {
+        Ok(Writer {
+            synced_len: file.metadata()?.len(),
+            file: BufWriter::with_capacity(capacity, file),
+            written_index: index,
+            durable_index: index,
+            unsynced: 0,
+            error: None,
+            synced: Arc::new(Condvar::new()),
+        })
+    }
+
+    // Switch to the file of a new active segment
+    pub(crate) fn replace(&mut self, file: File) -> io::Result<()> {
+        self.synced_len = file.metadata()?.len();
+        self.file = BufWriter::with_capacity(self.file.capacity(), file);
+        Ok(())
+    }
+
+    pub(crate) fn dirty(&self) -> bool {
+        self.written_index > self.durable_index || !self.file.buffer().is_empty()
+    }
+
+    // Write the buffer to the file and sync it: an fdatasync, or an fsync
+    // when the file size changed since the last fsync
+    pub(crate) fn sync(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        let len = self.file.get_ref().metadata()?.len();
+        if len != self.synced_len {
+            self.file.get_ref().sync_all()?;
+            self.synced_len = len;
+        } else {
+            self.file.get_ref().sync_data()?;
+        }
+        self.durable_index = self.written_index;
+        self.unsynced = 0;
+        self.synced.notify_all();
+        Ok(())
+    }
+}
+
+// Syncer syncs the writer every interval from a thread of its own, until
+// it is stopped or dropped
+#[derive(Debug)]
+pub(crate) struct Syncer {
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Syncer {
+    pub(crate) fn start(writer: Arc<Mutex<Writer>>, interval: Duration) -> Syncer {
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let signal = stop.clone();
+        let handle = thread::spawn(move || {
+            let (lock, cvar) = &*signal;
+            let mut stopped = lock.lock().expect("syncer lock");
+            loop {
+                // the flag is checked before waiting, stop may come first
+                stopped = cvar.wait_timeout_while(stopped, interval, |stopped| !*stopped).expect("syncer lock").0;
+                if *stopped {
+                    return;
+                }
+
+                let mut writer = writer.lock().expect("writer lock");
+                if writer.dirty() {
+                    if let Err(e) = writer.sync() {
+                        writer.error = Some(e);
+                    }
+                }
+            }
+        });
+
+        Syncer { stop, handle: Some(handle) }
+    }
+
+    pub(crate) fn stop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            let (lock, cvar) = &*self.stop;
+            *lock.lock().expect("syncer lock") = true;
+            cvar.notify_all();
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for Syncer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}